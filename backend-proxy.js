@@ -73,15 +73,34 @@ app.post("/api/products", async (req, res) => {
       batch_number,
       ingredients,
       certifications,
+      // Products submitted without an expiry date never expire (max nat64)
+      expiry_date = "18446744073709551615",
+      allergens = [],
+      location = "",
+      idempotency_key,
     } = req.body;
 
     // Format the command with proper escaping
     const ingredientsStr = ingredients.map((i) => `"${i}"`).join("; ");
     const certificationsStr = certifications.map((c) => `"${c}"`).join("; ");
+    const allergensStr = allergens.map((a) => `"${a}"`).join("; ");
+    // Retries with the same key return the product created by the first call
+    const idempotencyParam = idempotency_key ? `opt "${idempotency_key}"` : "null";
 
-    const command = `dfx canister call supply_chain create_product '("${name}", "${description}", "${manufacturer}", "${batch_number}", vec {${ingredientsStr}}, vec {${certificationsStr}})'`;
+    const command = `dfx canister call supply_chain create_product '("${name}", "${description}", "${manufacturer}", "${batch_number}", vec {${ingredientsStr}}, vec {${certificationsStr}}, ${expiry_date} : nat64, vec {${allergensStr}}, "${location}", ${idempotencyParam})'`;
 
     const result = await executeDfxCommand(command);
+
+    // Validation failures come back as an Err variant rather than a dfx error
+    if (result.includes("Err =")) {
+      console.error("Canister returned error:", result);
+      res.status(400).json({
+        success: false,
+        error: result,
+      });
+      return;
+    }
+
     res.json({
       success: true,
       result: result,
@@ -103,11 +122,15 @@ app.post("/api/events", async (req, res) => {
       product_id,
       event_type,
       location,
-      actor,
+      actor, // Id of a registered participant
       details,
       coordinates,
       temperature,
       humidity,
+      signature,
+      documents,
+      shipment_id,
+      tags,
     } = req.body;
 
     console.log("Parsed event data:", {
@@ -130,6 +153,7 @@ app.post("/api/events", async (req, res) => {
       Customs: "variant { Customs }",
       Delivery: "variant { Delivery }",
       Retail: "variant { Retail }",
+      SensorReading: "variant { SensorReading }",
     };
 
     const eventType = eventTypeMap[event_type] || "variant { Production }";
@@ -148,7 +172,25 @@ app.post("/api/events", async (req, res) => {
       ? `opt ${parseFloat(humidity).toFixed(1)}`
       : "null";
 
-    const command = `dfx canister call supply_chain add_supply_chain_event '("${product_id}", ${eventType}, "${location}", "${actor}", "${details}", ${coordsParam}, ${tempParam}, ${humidityParam})'`;
+    // Ed25519 signature by the actor's key over the bytes returned by get_signing_payload
+    const signatureParam = `vec { ${(signature || [])
+      .map((b) => `${b} : nat8`)
+      .join("; ")} }`;
+
+    // Off-chain documents anchored by their sha256 hex digest
+    const documentsParam = `vec { ${(documents || [])
+      .map(
+        (doc) =>
+          `record { name = "${doc.name}"; sha256 = "${doc.sha256}"; url = ${
+            doc.url ? `opt "${doc.url}"` : "null"
+          } }`
+      )
+      .join("; ")} }`;
+
+    const shipmentParam = shipment_id ? `opt "${shipment_id}"` : "null";
+    const tagsParam = `vec { ${(tags || []).map((t) => `"${t}"`).join("; ")} }`;
+
+    const command = `dfx canister call supply_chain add_supply_chain_event '("${product_id}", ${eventType}, "${location}", "${actor}", "${details}", ${coordsParam}, ${tempParam}, ${humidityParam}, ${signatureParam}, null, null, ${documentsParam}, ${shipmentParam}, ${tagsParam})'`;
 
     console.log("Executing command:", command);
 
@@ -156,7 +198,11 @@ app.post("/api/events", async (req, res) => {
     console.log("Command result:", result);
 
     // Check if the result contains an error
-    if (result.includes("Error:") || result.includes("error:")) {
+    if (
+      result.includes("Error:") ||
+      result.includes("error:") ||
+      result.includes("Err =")
+    ) {
       console.error("Canister returned error:", result);
       res.status(400).json({
         success: false,
@@ -474,6 +520,7 @@ function parseTraceEvents(dfxOutput, productId) {
 // Helper functions to extract specific fields from event records
 function extractEventType(recordText) {
   // Look for event type variants
+  if (recordText.includes("SensorReading")) return "Sensor Reading";
   if (recordText.includes("Production")) return "Production";
   if (recordText.includes("QualityCheck")) return "Quality Check";
   if (recordText.includes("Packaging")) return "Packaging";
@@ -29,7 +29,8 @@ echo "📝 Using unoptimized WASM file for deployment"
 
 # Deploy the canister
 echo "🚀 Deploying canister to local network..."
-dfx deploy
+# Empty admins and zero limits keep the defaults (deployer as admin)
+dfx deploy supply_chain --argument '(record { admins = vec {}; max_ingredients = 0 : nat32; rate_limit_per_minute = 0 : nat32 })'
 
 if [ $? -eq 0 ]; then
     echo "✅ Canister deployed successfully!"
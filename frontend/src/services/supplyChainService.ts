@@ -180,7 +180,7 @@ export const supplyChainService = {
         console.log("Parsing actual canister data...");
 
         // Extract products from the Candid structure
-        // Format: (vec { record { id = "1755781994917_1000"; name = "Coffee"; description = "..." } })
+        // Format: (vec { record { id = "prod_1755781994917_1000"; name = "Coffee"; description = "..." } })
         const products: any[] = [];
 
         // Use balanced brace parsing to extract complete records
@@ -1,46 +1,233 @@
-use candid::{CandidType, Deserialize};
+use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::{
     update,
     query,
     init,
+    pre_upgrade,
+    post_upgrade,
 };
-use std::collections::HashMap;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use ic_certified_map::{labeled, labeled_hash, AsHashTree, Hash, RbTree};
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+
+// Thin wrapper over the system API, so the canister logic also runs in native unit tests
+#[cfg(not(test))]
+mod system {
+    use candid::Principal;
+
+    pub fn caller() -> Principal {
+        ic_cdk::caller()
+    }
+
+    pub fn time() -> u64 {
+        ic_cdk::api::time()
+    }
+
+    pub fn print<S: AsRef<str>>(message: S) {
+        ic_cdk::print(message)
+    }
+
+    pub fn trap(message: &str) -> ! {
+        ic_cdk::trap(message)
+    }
+
+    pub fn canister_balance() -> u64 {
+        ic_cdk::api::canister_balance()
+    }
+
+    pub fn id() -> Principal {
+        ic_cdk::id()
+    }
+
+    pub fn stable_size_pages() -> u64 {
+        ic_cdk::api::stable::stable64_size()
+    }
+
+    pub fn set_certified_data(data: &[u8]) {
+        ic_cdk::api::set_certified_data(data)
+    }
+
+    pub fn data_certificate() -> Option<Vec<u8>> {
+        ic_cdk::api::data_certificate()
+    }
+}
+
+// Test double for the system API; caller and time are set per test thread
+#[cfg(test)]
+mod system {
+    use candid::Principal;
+    use std::cell::{Cell, RefCell};
+
+    thread_local! {
+        static CALLER: Cell<Principal> = const { Cell::new(Principal::anonymous()) };
+        static TIME_NANOS: Cell<u64> = const { Cell::new(1_700_000_000_000_000_000) };
+        static CERTIFIED_DATA: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub fn set_caller(caller: Principal) {
+        CALLER.set(caller);
+    }
+
+    // Set the clock in the units get_current_timestamp reports
+    pub fn set_timestamp(timestamp: u64) {
+        TIME_NANOS.set(timestamp * 1_000_000);
+    }
+
+    pub fn caller() -> Principal {
+        CALLER.get()
+    }
+
+    pub fn time() -> u64 {
+        TIME_NANOS.get()
+    }
+
+    pub fn print<S: AsRef<str>>(_message: S) {}
+
+    pub fn trap(message: &str) -> ! {
+        panic!("{}", message)
+    }
+
+    pub fn canister_balance() -> u64 {
+        u64::MAX
+    }
+
+    pub fn id() -> Principal {
+        Principal::management_canister()
+    }
+
+    pub fn stable_size_pages() -> u64 {
+        0
+    }
+
+    pub fn set_certified_data(data: &[u8]) {
+        CERTIFIED_DATA.set(data.to_vec());
+    }
+
+    pub fn certified_data() -> Vec<u8> {
+        CERTIFIED_DATA.with_borrow(|data| data.clone())
+    }
+
+    // Certificates only exist in non-replicated queries, which tests don't model
+    pub fn data_certificate() -> Option<Vec<u8>> {
+        None
+    }
+}
+
+// Id prefixes identifying the entity type, e.g. prod_1700000000_42
+const PRODUCT_ID_PREFIX: &str = "prod_";
+const EVENT_ID_PREFIX: &str = "evt_";
+const PARTICIPANT_ID_PREFIX: &str = "part_";
 
 // Simple ID generation function to replace UUID
-fn generate_id() -> String {
+// A monotonic counter keeps ids unique even within the same timestamp
+fn generate_id(prefix: &str) -> String {
     let timestamp = get_current_timestamp();
-    let random_part = (ic_cdk::api::time() % 10000) as u32;
-    format!("{}_{}", timestamp, random_part)
+    let counter = ID_COUNTER.with_borrow_mut(|counter| {
+        *counter += 1;
+        *counter
+    });
+    format!("{}{}_{}", prefix, timestamp, counter)
 }
 
 // Data structures for supply chain entities
-#[derive(CandidType, Clone)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct Product {
     pub id: String,
     pub name: String,
     pub description: String,
     pub manufacturer: String,
     pub batch_number: String,
-    pub production_date: u64, // Unix timestamp
+    pub production_date: u64, // Unix timestamp in milliseconds, like every timestamp in the canister
+    pub expiry_date: u64, // Unix timestamp in milliseconds
+    pub ingredients: Vec<String>,
+    pub certifications: Vec<String>,
+    pub allergens: Vec<String>, // Declared separately from ingredients
+    pub owner: Principal, // Principal that created the product
+    pub recalled: bool,
+    pub recall_reason: Option<String>,
+    pub archived: bool, // Hidden from default listings, reversible
+    pub temp_range: Option<(f64, f64)>, // Acceptable (min, max) °C for this product
+    pub components: Vec<String>, // Ids of the products this one is assembled from
+}
+
+// Fields supplied by callers when creating a product
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ProductInput {
+    pub name: String,
+    pub description: String,
+    pub manufacturer: String,
+    pub batch_number: String,
     pub ingredients: Vec<String>,
     pub certifications: Vec<String>,
+    pub expiry_date: u64, // Unix timestamp in milliseconds
+    pub allergens: Vec<String>,
+    pub location: String, // Where the genesis Production event took place
+    pub temp_range: Option<(f64, f64)>,
+}
+
+// Fields supplied by callers when recording an event
+#[derive(CandidType, Deserialize, Clone)]
+pub struct EventInput {
+    pub event_type: EventType,
+    pub location: String,
+    pub actor_id: String,
+    pub details: String,
+    pub coordinates: Option<(f64, f64)>,
+    pub temperature: Option<f64>,
+    pub humidity: Option<f64>,
+    pub signature: Vec<u8>,
+    pub distance_km: Option<f64>,
+    pub transport_mode: Option<TransportMode>,
+    pub documents: Vec<DocumentRef>,
+    pub shipment_id: Option<String>,
+    pub tags: Vec<String>,
+}
+
+// Off-chain document anchored to an event by its hash
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DocumentRef {
+    pub name: String,
+    pub sha256: String, // Hex-encoded sha256 of the document contents
+    pub url: Option<String>,
 }
 
-#[derive(CandidType, Clone)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct SupplyChainEvent {
     pub id: String,
     pub product_id: String,
     pub event_type: EventType,
     pub location: String,
-    pub timestamp: u64, // Unix timestamp
-    pub actor: String,
+    pub timestamp: u64, // Unix timestamp in milliseconds
+    pub actor_id: String, // Participant id of the actor
+    pub actor: String, // Participant name, resolved at insertion
+    pub actor_role: ParticipantRole,
     pub details: String,
     pub coordinates: Option<(f64, f64)>,
     pub temperature: Option<f64>,
     pub humidity: Option<f64>,
+    pub prev_hash: String, // Hash of the previous event in the trace
+    pub hash: String, // sha256 over prev_hash and this event's contents
+    pub signature: Vec<u8>, // Ed25519 signature by the actor over the event payload
+    pub signer_public_key: String, // Actor's public key at insertion time
+    pub signer_id: String, // Actor id the signature covers, kept when participants are merged
+    pub distance_km: Option<f64>, // Distance covered by this leg
+    pub transport_mode: Option<TransportMode>,
+    pub custody_to: Option<String>, // Recipient participant id for custody transfers
+    pub amends: Option<String>, // Id of the event this one corrects
+    pub superseded: bool, // Set once a later amendment replaces this event
+    pub documents: Vec<DocumentRef>, // Certificates, bills of lading, etc.
+    pub shipment_id: Option<String>, // Logistics id shared by products travelling together
+    pub created_by: Principal, // IC principal that submitted the event
+    pub tags: Vec<String>, // Free-form lowercase labels such as "fragile"
+    pub trace_index: u64, // Position in the trace, covered by the signature against replay
 }
 
-#[derive(CandidType, Deserialize, Clone)]
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
 pub enum EventType {
     Production,
     QualityCheck,
@@ -49,33 +236,331 @@ pub enum EventType {
     Customs,
     Delivery,
     Retail,
+    SensorReading, // IoT reading not tied to a lifecycle milestone
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum TransportMode {
+    Truck,
+    Ship,
+    Air,
+    Rail,
+}
+
+// Freight emission factors in kg CO2e per tonne-km, based on DEFRA averages
+fn emission_factor(mode: &TransportMode) -> f64 {
+    match mode {
+        TransportMode::Truck => 0.107,
+        TransportMode::Ship => 0.016,
+        TransportMode::Air => 1.130,
+        TransportMode::Rail => 0.028,
+    }
 }
 
-#[derive(CandidType, Clone)]
+// Every event type, in declaration order
+const ALL_EVENT_TYPES: [EventType; 8] = [
+    EventType::Production,
+    EventType::QualityCheck,
+    EventType::Packaging,
+    EventType::Shipping,
+    EventType::Customs,
+    EventType::Delivery,
+    EventType::Retail,
+    EventType::SensorReading,
+];
+
+// Canonical lifecycle stages used to score trace completeness (Customs is optional,
+// SensorReading is not a stage)
+const LIFECYCLE_STAGES: [EventType; 6] = [
+    EventType::Production,
+    EventType::QualityCheck,
+    EventType::Packaging,
+    EventType::Shipping,
+    EventType::Delivery,
+    EventType::Retail,
+];
+
+#[derive(CandidType, Deserialize, Clone)]
 pub struct SupplyChainTrace {
     pub product_id: String,
     pub events: Vec<SupplyChainEvent>,
-    pub created_at: u64, // Unix timestamp
-    pub last_updated: u64, // Unix timestamp
+    pub created_at: u64, // Unix timestamp in milliseconds
+    pub last_updated: u64, // Unix timestamp in milliseconds
+    pub current_custodian: Option<String>, // Participant id holding the goods
+    pub finalized: bool, // Closed after sale to a consumer; no further events
+}
+
+// Number of changelog entries retained for changes_since
+const MAX_CHANGELOG_ENTRIES: usize = 10_000;
+
+// Shortest search term accepted by substring queries
+const MIN_SEARCH_TERM_LEN: usize = 2;
+
+// Standard 2-8 °C cold chain window, used for products without their own temp_range
+const COLD_CHAIN_RANGE: (f64, f64) = (2.0, 8.0);
+
+// Maximum number of products returned by a single page
+const MAX_PAGE_SIZE: usize = 100;
+
+// Headline numbers for the dashboard
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Statistics {
+    pub total_products: u64,
+    pub total_events: u64,
+    pub total_participants: u64,
+    pub verified_participants: u64,
+    pub events_by_type: Vec<(EventType, u64)>, // Includes every type, even with zero events
+}
+
+// Aggregate temperature readings for a product's trace
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TempSummary {
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AuthVerdict {
+    pub authentic: bool,
+    pub reasons: Vec<String>, // Why the product failed verification, empty when authentic
+}
+
+// Collection sizes and memory usage for capacity monitoring
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MemStats {
+    pub products: u64,
+    pub events: u64,
+    pub traces: u64,
+    pub participants: u64,
+    pub heap_bytes: u64, // Wasm linear memory in use
+    pub stable_bytes: u64, // Stable memory in use, written on upgrade
+}
+
+// Lightweight header of a trace for list views
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TraceSummary {
+    pub product_id: String,
+    pub event_count: u64,
+    pub created_at: u64,
+    pub last_updated: u64,
+    pub latest_event_type: Option<EventType>,
+}
+
+// A product and the products it is assembled from, resolved recursively
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ComponentTree {
+    pub product_id: String,
+    pub name: String,
+    pub components: Vec<ComponentTree>,
+    pub truncated: bool, // Set when components below MAX_COMPONENT_DEPTH were left out
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct HealthStatus {
+    pub initialized: bool,
+    pub version: String,
+    pub uptime_seconds: u64, // Since the last install or upgrade
+}
+
+// Lifecycle differences between two products' traces
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TraceDiff {
+    pub a_counts: Vec<(EventType, u64)>, // Events per type in the first trace
+    pub b_counts: Vec<(EventType, u64)>,
+    pub only_in_a: Vec<EventType>, // Event types present in a but missing from b
+    pub only_in_b: Vec<EventType>,
+}
+
+// Combined verdict of every integrity check on a product's trace
+#[derive(CandidType, Deserialize, Clone)]
+pub struct IntegrityReport {
+    pub chronological_ok: bool,
+    pub hash_chain_ok: bool,
+    pub signatures_ok: bool,
+    pub cold_chain_ok: bool,
+    pub issues: Vec<String>, // Human-readable description of each failure
+}
+
+// Kind of product or trace mutation recorded in the changelog
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum ChangeKind {
+    ProductCreated,
+    ProductUpdated,
+    ProductDeleted,
+    EventAdded,
+    EventUpdated,
+    EventRemoved,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ChangeLogEntry {
+    pub seq: u64,
+    pub product_id: String,
+    pub kind: ChangeKind,
+}
+
+// Size limits on product fields, guarding canister memory against oversized input
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Limits {
+    pub max_ingredients: u64,
+    pub max_certifications: u64,
+    pub max_field_len: u64, // Maximum characters in any single string field
+}
+
+const DEFAULT_LIMITS: Limits = Limits {
+    max_ingredients: 100,
+    max_certifications: 50,
+    max_field_len: 256,
+};
+
+// Deploy-time configuration passed to init
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct InitConfig {
+    pub admins: Vec<Principal>, // Defaults to the deployer when empty
+    pub max_ingredients: u32, // 0 keeps the default limit
+    pub rate_limit_per_minute: u32, // 0 keeps the default limit
 }
 
+// Per-participant cap on events logged within a rolling window
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RateLimit {
+    pub max_events: u64,
+    pub window_secs: u64,
+}
+
+const DEFAULT_RATE_LIMIT: RateLimit = RateLimit {
+    max_events: 100,
+    window_secs: 60,
+};
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RateLimitStatus {
+    pub used: u64, // Events logged within the current window
+    pub max_events: u64,
+    pub window_secs: u64,
+}
+
+// Errors returned by fallible canister methods
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum RouteSyncError {
+    ProductNotFound,
+    TraceNotFound,
+    EventNotFound,
+    ParticipantNotFound,
+    ParticipantNotVerified,
+    InvalidSignature,
+    RoleNotPermitted, // Role not permitted for this event type
+    CustodyMismatch,
+    EventOutOfOrder, // Event timestamp precedes latest trace event
+    NoEvents,
+    ResponseTooLarge,
+    NoTemperatureData,
+    DuplicateBatch, // Duplicate batch for manufacturer
+    IncompleteTransitData,
+    PartialBatchFailure { inserted: Vec<String>, failed: Vec<u64> },
+    Unauthorized,
+    NotInitialized,
+    LastAdmin, // The last remaining admin cannot be removed
+    RateLimitExceeded,
+    TraceFinalized,
+    InvalidInput(String),
+}
+
+// Limits for bulk trace export, keeping responses under the IC size limit
+const MAX_EXPORT_IDS: usize = 50;
+const MAX_RESPONSE_EVENTS: usize = 5_000;
+
+// Most tags a single event may carry
+const MAX_EVENT_TAGS: usize = 20;
+
+// Events this close together with matching fields are reported as duplicates
+const DUPLICATE_EVENT_WINDOW_MS: u64 = 5_000;
+
+// Deepest level of an assembly resolved by get_component_tree
+const MAX_COMPONENT_DEPTH: usize = 10;
+
+// Most product ids accepted by verify_products_batch
+const MAX_VERIFY_IDS: usize = 100;
+
+// Cycle balance below which mutating calls log a warning (1T cycles)
+const DEFAULT_LOW_CYCLE_THRESHOLD: u64 = 1_000_000_000_000;
+
+// Build version reported by health()
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Size of a wasm and stable memory page
+const WASM_PAGE_SIZE_BYTES: u64 = 65_536;
+
+// QR codes stay reliably scannable below this payload size
+const MAX_QR_PAYLOAD_BYTES: usize = 200;
+
 // Global state variables
-static mut PRODUCTS: Option<HashMap<String, Product>> = None;
-static mut TRACES: Option<HashMap<String, SupplyChainTrace>> = None;
-static mut EVENTS: Option<HashMap<String, SupplyChainEvent>> = None;
-static mut PARTICIPANTS: Option<HashMap<String, Participant>> = None;
+thread_local! {
+    static PRODUCTS: RefCell<HashMap<String, Product>> = RefCell::new(HashMap::new());
+    static TRACES: RefCell<HashMap<String, SupplyChainTrace>> = RefCell::new(HashMap::new());
+    static EVENTS: RefCell<HashMap<String, SupplyChainEvent>> = RefCell::new(HashMap::new());
+    static PARTICIPANTS: RefCell<HashMap<String, Participant>> = RefCell::new(HashMap::new());
+    static ADMINS: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
+    static REQUIRE_VERIFIED_ACTORS: RefCell<bool> = const { RefCell::new(false) };
+    static LIMITS: RefCell<Limits> = const { RefCell::new(DEFAULT_LIMITS) };
+    static RATE_LIMIT: RefCell<RateLimit> = const { RefCell::new(DEFAULT_RATE_LIMIT) };
+    static EVENT_WINDOWS: RefCell<HashMap<String, VecDeque<u64>>> = RefCell::new(HashMap::new());
+    static IDEMPOTENCY_KEYS: RefCell<HashMap<(Principal, String), String>> = RefCell::new(HashMap::new());
+    static LOW_CYCLE_THRESHOLD: RefCell<u64> = const { RefCell::new(DEFAULT_LOW_CYCLE_THRESHOLD) };
+    static ID_COUNTER: RefCell<u64> = const { RefCell::new(0) };
+    static STARTED_AT: RefCell<u64> = const { RefCell::new(0) }; // Set by init/post_upgrade, not persisted
+    static SEQUENCE: RefCell<u64> = const { RefCell::new(0) };
+    static CHANGELOG: RefCell<VecDeque<ChangeLogEntry>> = const { RefCell::new(VecDeque::new()) };
+    // Digest of every product's authenticity certificate, rebuilt after upgrades
+    static CERTIFIED_DIGESTS: RefCell<RbTree<String, Hash>> = const { RefCell::new(RbTree::new()) };
+}
 
-#[derive(CandidType, Clone)]
+// Layout version of StableState. Bump it, and add a migration from the previous
+// layout in post_upgrade, whenever a persisted type changes shape; new fields on
+// persisted types should be Option so older snapshots keep decoding.
+const STABLE_STATE_VERSION: u32 = 1;
+
+// Snapshot of all state written to stable memory across upgrades
+#[derive(CandidType, Deserialize)]
+struct StableState {
+    products: HashMap<String, Product>,
+    traces: HashMap<String, SupplyChainTrace>,
+    events: HashMap<String, SupplyChainEvent>,
+    participants: HashMap<String, Participant>,
+    admins: HashSet<Principal>,
+    require_verified_actors: bool,
+    limits: Limits,
+    rate_limit: RateLimit,
+    event_windows: HashMap<String, VecDeque<u64>>,
+    idempotency_keys: HashMap<(Principal, String), String>,
+    low_cycle_threshold: u64,
+    id_counter: u64,
+    sequence: u64,
+    changelog: VecDeque<ChangeLogEntry>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
 pub struct Participant {
     pub id: String,
     pub name: String,
     pub role: ParticipantRole,
     pub location: String,
-    pub public_key: String,
+    pub public_key: String, // Hex-encoded Ed25519 public key
     pub is_verified: bool,
 }
 
+// Fields supplied by callers when registering a participant
 #[derive(CandidType, Deserialize, Clone)]
+pub struct ParticipantInput {
+    pub name: String,
+    pub role: ParticipantRole,
+    pub location: String,
+    pub public_key: String, // Hex-encoded Ed25519 public key
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
 pub enum ParticipantRole {
     Manufacturer,
     Supplier,
@@ -87,246 +572,4168 @@ pub enum ParticipantRole {
 
 // Helper function to get current timestamp
 fn get_current_timestamp() -> u64 {
-    ic_cdk::api::time() / 1_000_000 // Convert nanoseconds to seconds
+    system::time() / 1_000_000 // Convert nanoseconds to milliseconds
 }
 
-// Initialize the canister
-#[init]
-fn init() {
-    unsafe {
-        PRODUCTS = Some(HashMap::new());
-        TRACES = Some(HashMap::new());
-        EVENTS = Some(HashMap::new());
-        PARTICIPANTS = Some(HashMap::new());
-        
-        // Debug: Log initialization
-        ic_cdk::print("Canister initialized - state variables set");
-    }
+// Mean Earth radius used for great-circle distances
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+// Helper function to check a latitude/longitude pair is on the globe
+fn is_valid_coordinate(lat: f64, lon: f64) -> bool {
+    (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon)
 }
 
-// Product management functions
-#[update]
-fn create_product(
-    name: String,
-    description: String,
-    manufacturer: String,
-    batch_number: String,
-    ingredients: Vec<String>,
-    certifications: Vec<String>,
-) -> String {
-    let product_id = generate_id();
-    let product = Product {
-        id: product_id.clone(),
-        name,
-        description,
-        manufacturer,
-        batch_number,
-        production_date: get_current_timestamp(),
-        ingredients,
-        certifications,
-    };
+// Helper function to compute the haversine distance between two points in km
+fn haversine_km(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (lat1, lon1) = (from.0.to_radians(), from.1.to_radians());
+    let (lat2, lon2) = (to.0.to_radians(), to.1.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = lon2 - lon1;
 
-    unsafe {
-        if let Some(products) = &mut PRODUCTS {
-            products.insert(product_id.clone(), product);
-            // Debug: Log product creation
-            ic_cdk::print(format!("Product created with ID: {}, total products: {}", product_id, products.len()));
-        } else {
-            // Debug: Log if PRODUCTS is None
-            ic_cdk::print("ERROR: PRODUCTS is None - state not initialized!");
-        }
+    let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
 
-        // Create initial trace
-        let trace = SupplyChainTrace {
-            product_id: product_id.clone(),
-            events: Vec::new(),
-            created_at: get_current_timestamp(),
-            last_updated: get_current_timestamp(),
-        };
-        
-        if let Some(traces) = &mut TRACES {
-            traces.insert(product_id.clone(), trace);
-            // Debug: Log trace creation
-            ic_cdk::print(format!("Trace created for product: {}, total traces: {}", product_id, traces.len()));
-        } else {
-            // Debug: Log if TRACES is None
-            ic_cdk::print("ERROR: TRACES is None - state not initialized!");
-        }
-    }
-    product_id
+// Helper function to approximate the distance in km from a point to a route segment.
+// Projects onto a local equirectangular plane to find the closest point, then
+// measures the haversine distance to it.
+fn distance_to_segment_km(point: (f64, f64), start: (f64, f64), end: (f64, f64)) -> f64 {
+    let scale = point.0.to_radians().cos();
+    let to_plane = |(lat, lon): (f64, f64)| (lon * scale, lat);
+    let (px, py) = to_plane(point);
+    let (ax, ay) = to_plane(start);
+    let (bx, by) = to_plane(end);
+
+    let (dx, dy) = (bx - ax, by - ay);
+    let length_sq = dx * dx + dy * dy;
+    let t = if length_sq == 0.0 {
+        0.0
+    } else {
+        (((px - ax) * dx + (py - ay) * dy) / length_sq).clamp(0.0, 1.0)
+    };
+
+    let closest = (start.0 + t * (end.0 - start.0), start.1 + t * (end.1 - start.1));
+    haversine_km(point, closest)
 }
 
-#[update]
-fn add_supply_chain_event(
-    product_id: String,
-    event_type: EventType,
-    location: String,
-    actor: String,
-    details: String,
-    coordinates: Option<(f64, f64)>,
-    temperature: Option<f64>,
-    humidity: Option<f64>,
-) -> String {
-    let event_id = generate_id();
-    let event = SupplyChainEvent {
-        id: event_id.clone(),
-        product_id: product_id.clone(),
-        event_type,
-        location,
-        timestamp: get_current_timestamp(),
-        actor,
-        details,
-        coordinates,
-        temperature,
-        humidity,
+// Helper function to compute the chained hash of an event: a sha256 over the Candid
+// encoding of the whole event, prev_hash included, with `hash` itself blanked.
+// `superseded` is blanked too, since amend_event flips it after the event is chained.
+fn compute_event_hash(event: &SupplyChainEvent) -> String {
+    let canonical = SupplyChainEvent {
+        hash: String::new(),
+        superseded: false,
+        ..event.clone()
     };
+    let bytes = candid::encode_one(&canonical).expect("Failed to encode event for hashing");
+    hex::encode(Sha256::digest(bytes))
+}
 
-    unsafe {
-        // Verify product exists
-        if let Some(products) = &PRODUCTS {
-            if !products.contains_key(&product_id) {
-                return "Product not found".to_string();
-            }
-        }
+// Helper function to check whether a participant role may log an event type
+fn is_role_permitted(role: &ParticipantRole, event_type: &EventType) -> bool {
+    use ParticipantRole::*;
+    match event_type {
+        EventType::Production => matches!(role, Manufacturer),
+        EventType::QualityCheck => matches!(role, Manufacturer | Supplier | Auditor),
+        EventType::Packaging => matches!(role, Manufacturer | Supplier),
+        EventType::Shipping => matches!(role, Manufacturer | Supplier | Distributor),
+        EventType::Customs => matches!(role, Distributor | Auditor),
+        EventType::Delivery => matches!(role, Distributor | Retailer),
+        EventType::Retail => matches!(role, Retailer),
+        EventType::SensorReading => !matches!(role, Consumer),
+    }
+}
 
-        // Add event to events collection
-        if let Some(events) = &mut EVENTS {
-            events.insert(event_id.clone(), event.clone());
-        }
+// Helper function listing the event types that must already appear in a trace
+// before an event of the given type is physically possible
+fn required_predecessors(event_type: &EventType) -> &'static [EventType] {
+    match event_type {
+        EventType::Production | EventType::SensorReading => &[],
+        EventType::QualityCheck | EventType::Packaging | EventType::Shipping => &[EventType::Production],
+        EventType::Customs | EventType::Delivery => &[EventType::Shipping],
+        EventType::Retail => &[EventType::Delivery],
+    }
+}
 
-        // Add event to product trace
-        if let Some(traces) = &mut TRACES {
-            if let Some(trace) = traces.get_mut(&product_id) {
-                trace.events.push(event);
-                trace.last_updated = get_current_timestamp();
-                // Debug: Log event addition to trace
-                ic_cdk::print(format!("Event added to trace for product: {}, total events in trace: {}", product_id, trace.events.len()));
-            } else {
-                // Debug: Log if trace not found
-                ic_cdk::print(format!("ERROR: Trace not found for product: {}", product_id));
-            }
-        } else {
-            // Debug: Log if TRACES is None
-            ic_cdk::print("ERROR: TRACES is None - state not initialized!");
-        }
+// Helper function to compute a sha256 digest over a product's descriptive fields
+fn product_digest(product: &Product) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for field in [
+        &product.id,
+        &product.name,
+        &product.description,
+        &product.manufacturer,
+        &product.batch_number,
+    ] {
+        hasher.update(field.as_bytes());
+        hasher.update(b"|");
     }
-    event_id
+    hasher.update(product.production_date.to_string().as_bytes());
+    hasher.update(b"|");
+    hasher.update(product.expiry_date.to_string().as_bytes());
+    hasher.update(b"|");
+    hasher.update(product.ingredients.join(",").as_bytes());
+    hasher.update(b"|");
+    hasher.update(product.certifications.join(",").as_bytes());
+    hasher.finalize().into()
 }
 
-#[update]
-fn register_participant(
-    name: String,
-    role: ParticipantRole,
-    location: String,
-    public_key: String,
-) -> String {
-    let participant_id = generate_id();
-    let participant = Participant {
-        id: participant_id.clone(),
-        name,
-        role,
-        location,
-        public_key,
-        is_verified: false,
+// Helper function to build the bytes an actor signs for an event: the Candid encoding
+// of (product_id, trace_index, input) with the signature left empty and tags normalized
+// as stored. Every caller-supplied field is covered, and the trace index pins the
+// signature to one position in one trace so it cannot be replayed.
+fn event_signing_payload(product_id: &str, trace_index: u64, input: &EventInput) -> Vec<u8> {
+    let unsigned = EventInput {
+        signature: Vec::new(),
+        tags: normalize_tags(input.tags.clone()),
+        ..input.clone()
     };
+    candid::encode_args((product_id, trace_index, unsigned)).expect("Failed to encode event signing payload")
+}
 
-    unsafe {
-        if let Some(participants) = &mut PARTICIPANTS {
-            participants.insert(participant_id.clone(), participant);
-        }
-    }
-    participant_id
+// Helper function to find the index the next event appended to a trace will get
+fn next_trace_index(product_id: &str) -> u64 {
+    TRACES.with_borrow(|traces| traces.get(product_id).map_or(0, |trace| trace.events.len() as u64))
 }
 
-// Test method to debug Candid interface
-#[update]
-fn test_simple() -> String {
-    "Hello from canister".to_string()
+// Helper function to verify an Ed25519 signature against a hex-encoded public key
+fn verify_signature(public_key: &str, payload: &[u8], signature: &[u8]) -> bool {
+    let Ok(key_bytes) = hex::decode(public_key) else {
+        return false;
+    };
+    let Ok(key_bytes) = <[u8; 32]>::try_from(key_bytes.as_slice()) else {
+        return false;
+    };
+    let Ok(key) = VerifyingKey::from_bytes(&key_bytes) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(signature) else {
+        return false;
+    };
+    key.verify(payload, &signature).is_ok()
 }
 
-// Query functions
-#[query]
-fn get_product(product_id: String) -> Result<Product, String> {
-    unsafe {
-        if let Some(products) = &PRODUCTS {
-            products.get(&product_id)
-                .cloned()
-                .ok_or("Product not found".to_string())
-        } else {
-            Err("Products not initialized".to_string())
-        }
+// Helper function to collect the events in a product's trace matching a predicate
+fn filter_trace_events<F>(product_id: &str, predicate: F) -> Result<Vec<SupplyChainEvent>, RouteSyncError>
+where
+    F: Fn(&SupplyChainEvent) -> bool,
+{
+    if !PRODUCTS.with_borrow(|products| products.contains_key(product_id)) {
+        return Err(RouteSyncError::ProductNotFound);
     }
+
+    Ok(TRACES.with_borrow(|traces| {
+        traces.get(product_id)
+            .map(|trace| trace.events.iter().filter(|event| predicate(event)).cloned().collect())
+            .unwrap_or_default()
+    }))
 }
 
-#[query]
-fn get_supply_chain_trace(product_id: String) -> Option<SupplyChainTrace> {
-    unsafe {
-        if let Some(traces) = &TRACES {
-            traces.get(&product_id).cloned()
-        } else {
-            None
-        }
+// Helper function to log a warning when the cycle balance drops below the threshold
+fn warn_if_low_cycles() {
+    let balance = system::canister_balance();
+    let threshold = LOW_CYCLE_THRESHOLD.with_borrow(|threshold| *threshold);
+    if balance < threshold {
+        system::print(format!("WARNING: Cycle balance {} is below threshold {}", balance, threshold));
     }
 }
 
-#[query]
-fn get_all_products() -> Vec<Product> {
-    unsafe {
-        if let Some(products) = &PRODUCTS {
-            products.values().cloned().collect()
-        } else {
-            Vec::new()
+// Helper function to bump the global sequence and record a product/trace mutation.
+// Every product/trace mutation passes through here, so it also checks the cycle balance.
+fn record_change(product_id: &str, kind: ChangeKind) {
+    warn_if_low_cycles();
+
+    let seq = SEQUENCE.with_borrow_mut(|sequence| {
+        *sequence += 1;
+        *sequence
+    });
+    CHANGELOG.with_borrow_mut(|changelog| {
+        changelog.push_back(ChangeLogEntry {
+            seq,
+            product_id: product_id.to_string(),
+            kind,
+        });
+        // Only the most recent entries are kept to bound memory
+        while changelog.len() > MAX_CHANGELOG_ENTRIES {
+            changelog.pop_front();
         }
-    }
+    });
+    certify_product(product_id);
 }
 
-#[query]
-fn get_participants() -> Vec<Participant> {
-    unsafe {
-        if let Some(participants) = &PARTICIPANTS {
-            participants.values().cloned().collect()
+// Helper function to chain an event onto its product's trace and store it,
+// filling in prev_hash and hash. Events older than the latest one are rejected.
+fn append_event(event: SupplyChainEvent) -> Result<String, RouteSyncError> {
+    let product_id = event.product_id.clone();
+    let event_id = chain_event(event)?;
+    touch_trace(&product_id);
+    Ok(event_id)
+}
+
+// Helper function to store a chained event without bumping the trace's last_updated
+fn chain_event(mut event: SupplyChainEvent) -> Result<String, RouteSyncError> {
+    let product_id = event.product_id.clone();
+    let event_id = event.id.clone();
+
+    if TRACES.with_borrow(|traces| traces.get(&product_id).is_some_and(|trace| trace.finalized)) {
+        return Err(RouteSyncError::TraceFinalized);
+    }
+    let last = TRACES.with_borrow(|traces| {
+        traces.get(&product_id)
+            .and_then(|trace| trace.events.last())
+            .map(|last| (last.timestamp, last.hash.clone()))
+    });
+    if last.as_ref().is_some_and(|(timestamp, _)| event.timestamp < *timestamp) {
+        return Err(RouteSyncError::EventOutOfOrder);
+    }
+
+    // Chain the new event onto the last one in the trace
+    event.trace_index = next_trace_index(&product_id);
+    event.prev_hash = last.map(|(_, hash)| hash).unwrap_or_default();
+    event.hash = compute_event_hash(&event);
+
+    // Add event to events collection
+    EVENTS.with_borrow_mut(|events| {
+        events.insert(event_id.clone(), event.clone());
+    });
+
+    // Add event to product trace
+    TRACES.with_borrow_mut(|traces| {
+        if let Some(trace) = traces.get_mut(&product_id) {
+            trace.events.push(event);
+            // Debug: Log event addition to trace
+            system::print(format!("Event added to trace for product: {}, total events in trace: {}", product_id, trace.events.len()));
         } else {
-            Vec::new()
+            // Debug: Log if trace not found
+            system::print(format!("ERROR: Trace not found for product: {}", product_id));
+        }
+    });
+    record_change(&product_id, ChangeKind::EventAdded);
+
+    Ok(event_id)
+}
+
+// Helper function to mark a product's trace as updated now
+fn touch_trace(product_id: &str) {
+    TRACES.with_borrow_mut(|traces| {
+        if let Some(trace) = traces.get_mut(product_id) {
+            trace.last_updated = get_current_timestamp();
+        }
+    });
+}
+
+// Helper function to lowercase tags, dropping empty and duplicate ones
+fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    tags.into_iter()
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty() && seen.insert(tag.clone()))
+        .collect()
+}
+
+// Helper function to validate sensor and location readings on an event input
+fn validate_event_input(input: &EventInput) -> Result<(), RouteSyncError> {
+    if let Some((lat, lon)) = input.coordinates {
+        if !is_valid_coordinate(lat, lon) {
+            return Err(RouteSyncError::InvalidInput("Invalid coordinates".to_string()));
+        }
+    }
+    if input.temperature.is_some_and(|temperature| !temperature.is_finite()) {
+        return Err(RouteSyncError::InvalidInput("Invalid temperature".to_string()));
+    }
+    if input.humidity.is_some_and(|humidity| !humidity.is_finite()) {
+        return Err(RouteSyncError::InvalidInput("Invalid humidity".to_string()));
+    }
+    if input.distance_km.is_some_and(|distance| !distance.is_finite() || distance < 0.0) {
+        return Err(RouteSyncError::InvalidInput("Invalid distance".to_string()));
+    }
+    for document in &input.documents {
+        if document.sha256.len() != 64 || !document.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(RouteSyncError::InvalidInput("Document sha256 must be 64 hex characters".to_string()));
         }
     }
+    Ok(())
 }
 
-#[query]
-fn verify_product_authenticity(product_id: String) -> Result<bool, String> {
-    unsafe {
-        // Check if product exists
-        if let Some(products) = &PRODUCTS {
-            if !products.contains_key(&product_id) {
-                return Err("Product not found".to_string());
+// Helper function to validate caller-supplied event fields and build the event
+fn build_event(product_id: &str, input: EventInput) -> Result<SupplyChainEvent, RouteSyncError> {
+    let created_by = authenticated_caller()?;
+    validate_event_input(&input)?;
+    let tags = normalize_tags(input.tags.clone());
+    if tags.len() > MAX_EVENT_TAGS {
+        return Err(RouteSyncError::InvalidInput(format!("At most {} tags per event", MAX_EVENT_TAGS)));
+    }
+
+    // Resolve the actor against registered participants
+    let actor = PARTICIPANTS.with_borrow(|participants| participants.get(&input.actor_id).cloned())
+        .ok_or(RouteSyncError::ParticipantNotFound)?;
+    if REQUIRE_VERIFIED_ACTORS.with_borrow(|required| *required) && !actor.is_verified {
+        return Err(RouteSyncError::ParticipantNotVerified);
+    }
+    if !is_role_permitted(&actor.role, &input.event_type) {
+        return Err(RouteSyncError::RoleNotPermitted);
+    }
+
+    // The actor must have signed the event payload with their registered key
+    let trace_index = next_trace_index(product_id);
+    let payload = event_signing_payload(product_id, trace_index, &input);
+    if !verify_signature(&actor.public_key, &payload, &input.signature) {
+        return Err(RouteSyncError::InvalidSignature);
+    }
+
+    Ok(SupplyChainEvent {
+        id: generate_id(EVENT_ID_PREFIX),
+        product_id: product_id.to_string(),
+        event_type: input.event_type,
+        location: input.location,
+        timestamp: get_current_timestamp(),
+        actor_id: input.actor_id.clone(),
+        actor: actor.name,
+        actor_role: actor.role,
+        details: input.details,
+        coordinates: input.coordinates,
+        temperature: input.temperature,
+        humidity: input.humidity,
+        prev_hash: String::new(),
+        hash: String::new(),
+        signature: input.signature,
+        signer_public_key: actor.public_key,
+        signer_id: input.actor_id.clone(),
+        distance_km: input.distance_km,
+        transport_mode: input.transport_mode,
+        custody_to: None,
+        amends: None,
+        superseded: false,
+        documents: input.documents,
+        shipment_id: input.shipment_id,
+        created_by,
+        tags,
+        trace_index,
+    })
+}
+
+// Helper function to find the first event whose timestamp precedes its predecessor
+fn first_out_of_order(events: &[SupplyChainEvent]) -> Option<usize> {
+    events.windows(2)
+        .position(|pair| pair[1].timestamp < pair[0].timestamp)
+        .map(|index| index + 1)
+}
+
+// Helper function to find the first event whose hash or link to its predecessor is wrong
+fn first_broken_link(events: &[SupplyChainEvent]) -> Option<usize> {
+    // Recompute every hash and check each links to its predecessor
+    let mut expected_prev = String::new();
+    for (index, event) in events.iter().enumerate() {
+        let hash = compute_event_hash(event);
+        if event.prev_hash != expected_prev || event.hash != hash {
+            return Some(index);
+        }
+        expected_prev = hash;
+    }
+    None
+}
+
+// Helper function to check an event's stored signature. System-generated events
+// (product creation, amendments) carry no signature and always fail this check.
+fn has_valid_signature(event: &SupplyChainEvent) -> bool {
+    let input = EventInput {
+        event_type: event.event_type.clone(),
+        location: event.location.clone(),
+        actor_id: event.signer_id.clone(),
+        details: event.details.clone(),
+        coordinates: event.coordinates,
+        temperature: event.temperature,
+        humidity: event.humidity,
+        signature: Vec::new(),
+        distance_km: event.distance_km,
+        transport_mode: event.transport_mode.clone(),
+        documents: event.documents.clone(),
+        shipment_id: event.shipment_id.clone(),
+        tags: event.tags.clone(),
+    };
+    let payload = event_signing_payload(&event.product_id, event.trace_index, &input);
+    verify_signature(&event.signer_public_key, &payload, &event.signature)
+}
+
+// Helper function to apply a change to an event in both EVENTS and its trace
+fn modify_event<F>(product_id: &str, event_id: &str, change: F)
+where
+    F: Fn(&mut SupplyChainEvent),
+{
+    EVENTS.with_borrow_mut(|events| {
+        if let Some(event) = events.get_mut(event_id) {
+            change(event);
+        }
+    });
+    TRACES.with_borrow_mut(|traces| {
+        if let Some(event) = traces.get_mut(product_id)
+            .and_then(|trace| trace.events.iter_mut().find(|event| event.id == event_id))
+        {
+            change(event);
+        }
+    });
+    record_change(product_id, ChangeKind::EventUpdated);
+}
+
+// Helper function to refuse rewriting a trace whose chain is already broken before
+// `index`, so authorised edits never paper over an earlier break
+fn ensure_chain_intact_before(product_id: &str, index: usize) -> Result<(), RouteSyncError> {
+    let broken = TRACES.with_borrow(|traces| {
+        traces.get(product_id)
+            .and_then(|trace| first_broken_link(&trace.events[..index.min(trace.events.len())]))
+    });
+    match broken {
+        Some(index) => Err(RouteSyncError::InvalidInput(format!(
+            "Hash chain of {} is broken at index {}",
+            product_id, index
+        ))),
+        None => Ok(()),
+    }
+}
+
+// Helper function to recompute the hashes of a trace from `start` onward after
+// events from there on were rewritten
+fn rehash_trace_from(product_id: &str, start: usize) {
+    let rehashed: Vec<(String, String, String)> = TRACES.with_borrow_mut(|traces| {
+        let Some(trace) = traces.get_mut(product_id) else {
+            return Vec::new();
+        };
+        let mut prev_hash = start.checked_sub(1)
+            .and_then(|index| trace.events.get(index))
+            .map(|event| event.hash.clone())
+            .unwrap_or_default();
+        trace.events.iter_mut()
+            .skip(start)
+            .map(|event| {
+                event.prev_hash = prev_hash.clone();
+                event.hash = compute_event_hash(event);
+                prev_hash = event.hash.clone();
+                (event.id.clone(), event.prev_hash.clone(), event.hash.clone())
+            })
+            .collect()
+    });
+
+    // Keep the copies in EVENTS in sync with the trace
+    EVENTS.with_borrow_mut(|events| {
+        for (event_id, prev_hash, hash) in rehashed {
+            if let Some(event) = events.get_mut(&event_id) {
+                event.prev_hash = prev_hash;
+                event.hash = hash;
             }
-        } else {
-            return Err("Products not initialized".to_string());
         }
+    });
+    certify_product(product_id);
+}
+
+// Helper function to count a participant's events within the current window,
+// dropping timestamps that have aged out of it
+fn events_in_rate_window(participant_id: &str) -> u64 {
+    let window_secs = RATE_LIMIT.with_borrow(|rate_limit| rate_limit.window_secs);
+    // Timestamps are in milliseconds
+    let cutoff = get_current_timestamp().saturating_sub(window_secs.saturating_mul(1000));
+    EVENT_WINDOWS.with_borrow_mut(|windows| {
+        let Some(window) = windows.get_mut(participant_id) else {
+            return 0;
+        };
+        while window.front().is_some_and(|timestamp| *timestamp <= cutoff) {
+            window.pop_front();
+        }
+        let used = window.len() as u64;
+        if window.is_empty() {
+            windows.remove(participant_id);
+        }
+        used
+    })
+}
 
-        // Check if trace exists and has events
-        if let Some(traces) = &TRACES {
-            if let Some(trace) = traces.get(&product_id) {
-                if trace.events.is_empty() {
-                    return Ok(false);
-                }
-                
-                // Basic verification: check if events are in chronological order
-                let mut prev_timestamp = trace.events[0].timestamp;
-                for event in &trace.events[1..] {
-                    if event.timestamp < prev_timestamp {
-                        return Ok(false);
-                    }
-                    prev_timestamp = event.timestamp;
-                }
-                
-                Ok(true)
-            } else {
-                Ok(false)
+// Helper function to reject a participant that has used up its rate limit
+fn check_rate_limit(participant_id: &str) -> Result<(), RouteSyncError> {
+    let max_events = RATE_LIMIT.with_borrow(|rate_limit| rate_limit.max_events);
+    if events_in_rate_window(participant_id) >= max_events {
+        return Err(RouteSyncError::RateLimitExceeded);
+    }
+    Ok(())
+}
+
+// Helper function to count an event against a participant's rate limit
+fn record_rate_usage(participant_id: &str) {
+    EVENT_WINDOWS.with_borrow_mut(|windows| {
+        windows.entry(participant_id.to_string())
+            .or_default()
+            .push_back(get_current_timestamp());
+    });
+}
+
+// Helper function to check whether target is reachable through the component graph
+fn reaches_component(products: &HashMap<String, Product>, from: &str, target: &str) -> bool {
+    let mut pending = vec![from.to_string()];
+    let mut visited = HashSet::new();
+    while let Some(product_id) = pending.pop() {
+        if product_id == target {
+            return true;
+        }
+        if !visited.insert(product_id.clone()) {
+            continue;
+        }
+        if let Some(product) = products.get(&product_id) {
+            pending.extend(product.components.iter().cloned());
+        }
+    }
+    false
+}
+
+// Helper function to build a component tree, cutting off below MAX_COMPONENT_DEPTH
+fn build_component_tree(products: &HashMap<String, Product>, product: &Product, depth: usize) -> ComponentTree {
+    let at_limit = depth >= MAX_COMPONENT_DEPTH;
+    let components = if at_limit {
+        Vec::new()
+    } else {
+        // Components deleted since they were linked are skipped
+        product.components.iter()
+            .filter_map(|component_id| products.get(component_id))
+            .map(|component| build_component_tree(products, component, depth + 1))
+            .collect()
+    };
+    ComponentTree {
+        product_id: product.id.clone(),
+        name: product.name.clone(),
+        components,
+        truncated: at_limit && !product.components.is_empty(),
+    }
+}
+
+// Helper function to ensure the caller owns the given product
+fn ensure_owner(product_id: &str) -> Result<(), RouteSyncError> {
+    let caller = system::caller();
+    PRODUCTS.with_borrow(|products| match products.get(product_id) {
+        Some(product) if product.owner == caller => Ok(()),
+        Some(_) => Err(RouteSyncError::Unauthorized),
+        None => Err(RouteSyncError::ProductNotFound),
+    })
+}
+
+// Helper function to ensure the caller is the canister admin. Every admin endpoint is
+// an update passing through here, so it also checks the cycle balance.
+fn ensure_admin() -> Result<(), RouteSyncError> {
+    warn_if_low_cycles();
+    if ADMINS.with_borrow(|admins| admins.contains(&system::caller())) {
+        Ok(())
+    } else {
+        Err(RouteSyncError::Unauthorized)
+    }
+}
+
+// Initialize the canister from deploy-time configuration. Empty or zero fields
+// keep their defaults: the deployer as sole admin and the default limits.
+// Upgrades restore the persisted configuration instead of re-reading these args.
+#[init]
+fn init(config: InitConfig) {
+    // State maps are allocated lazily by thread_local!
+    let admins = if config.admins.is_empty() {
+        vec![system::caller()]
+    } else {
+        config.admins
+    };
+    // Anyone can call as the anonymous principal, so it must never hold admin rights
+    if admins.contains(&Principal::anonymous()) {
+        system::trap("Anonymous principal cannot be an admin");
+    }
+    ADMINS.set(admins.into_iter().collect());
+    if config.max_ingredients > 0 {
+        LIMITS.with_borrow_mut(|limits| limits.max_ingredients = config.max_ingredients as u64);
+    }
+    if config.rate_limit_per_minute > 0 {
+        RATE_LIMIT.set(RateLimit {
+            max_events: config.rate_limit_per_minute as u64,
+            window_secs: 60,
+        });
+    }
+    STARTED_AT.set(get_current_timestamp());
+
+    // Debug: Log initialization
+    system::print("Canister initialized - state variables set");
+}
+
+// Helper function to move all state into a snapshot for stable memory
+fn snapshot_state() -> StableState {
+    StableState {
+        products: PRODUCTS.take(),
+        traces: TRACES.take(),
+        events: EVENTS.take(),
+        participants: PARTICIPANTS.take(),
+        admins: ADMINS.take(),
+        require_verified_actors: REQUIRE_VERIFIED_ACTORS.with_borrow(|required| *required),
+        limits: LIMITS.with_borrow(|limits| limits.clone()),
+        rate_limit: RATE_LIMIT.with_borrow(|rate_limit| rate_limit.clone()),
+        event_windows: EVENT_WINDOWS.take(),
+        idempotency_keys: IDEMPOTENCY_KEYS.take(),
+        low_cycle_threshold: LOW_CYCLE_THRESHOLD.with_borrow(|threshold| *threshold),
+        id_counter: ID_COUNTER.with_borrow(|counter| *counter),
+        sequence: SEQUENCE.with_borrow(|sequence| *sequence),
+        changelog: CHANGELOG.take(),
+    }
+}
+
+// Helper function to load a snapshot taken by snapshot_state back into state
+fn restore_state(state: StableState) {
+    PRODUCTS.set(state.products);
+    TRACES.set(state.traces);
+    EVENTS.set(state.events);
+    PARTICIPANTS.set(state.participants);
+    ADMINS.set(state.admins);
+    REQUIRE_VERIFIED_ACTORS.set(state.require_verified_actors);
+    LIMITS.set(state.limits);
+    RATE_LIMIT.set(state.rate_limit);
+    EVENT_WINDOWS.set(state.event_windows);
+    IDEMPOTENCY_KEYS.set(state.idempotency_keys);
+    LOW_CYCLE_THRESHOLD.set(state.low_cycle_threshold);
+    ID_COUNTER.set(state.id_counter);
+    SEQUENCE.set(state.sequence);
+    CHANGELOG.set(state.changelog);
+    STARTED_AT.set(get_current_timestamp());
+    // Certified data does not survive an upgrade, so publish it again
+    certify_all_products();
+}
+
+// Save all state to stable memory before the code is replaced, tagged with its layout version
+#[pre_upgrade]
+fn pre_upgrade() {
+    ic_cdk::storage::stable_save((STABLE_STATE_VERSION, snapshot_state()))
+        .expect("Failed to save state to stable memory");
+}
+
+// Restore state saved by pre_upgrade. Any snapshot that cannot be decoded traps,
+// which rolls the upgrade back and leaves the old code and its data in place.
+#[post_upgrade]
+fn post_upgrade() {
+    match ic_cdk::storage::stable_restore::<(u32, StableState)>() {
+        Ok((version, state)) if version == STABLE_STATE_VERSION => restore_state(state),
+        Ok((version, _)) => system::trap(&format!("Unsupported stable state version {}", version)),
+        Err(e) => system::trap(&format!("Failed to restore state from stable memory: {}", e)),
+    }
+}
+
+// Helper function to reject incomplete product input before it is stored
+fn validate_product_input(input: &ProductInput) -> Result<(), RouteSyncError> {
+    validate_product_name(&input.name)?;
+    if input.manufacturer.trim().is_empty() {
+        return Err(RouteSyncError::InvalidInput("Manufacturer cannot be empty".to_string()));
+    }
+    if input.batch_number.trim().is_empty() {
+        return Err(RouteSyncError::InvalidInput("Batch number cannot be empty".to_string()));
+    }
+    validate_ingredients(&input.ingredients)?;
+    if let Some((min, max)) = input.temp_range {
+        validate_temp_range(min, max)?;
+    }
+    let fields = [&input.name, &input.description, &input.manufacturer, &input.batch_number, &input.location];
+    check_field_lengths(fields.into_iter().chain(&input.allergens))?;
+    check_list_limits(&input.ingredients, &input.certifications)
+}
+
+// Helper function to reject a blank product name
+fn validate_product_name(name: &str) -> Result<(), RouteSyncError> {
+    if name.trim().is_empty() {
+        return Err(RouteSyncError::InvalidInput("Product name cannot be empty".to_string()));
+    }
+    Ok(())
+}
+
+// Helper function to reject blank ingredient entries
+fn validate_ingredients(ingredients: &[String]) -> Result<(), RouteSyncError> {
+    if ingredients.iter().any(|ingredient| ingredient.trim().is_empty()) {
+        return Err(RouteSyncError::InvalidInput("Ingredients cannot contain empty entries".to_string()));
+    }
+    Ok(())
+}
+
+// Helper function to check a temperature range is finite and ordered
+fn validate_temp_range(min: f64, max: f64) -> Result<(), RouteSyncError> {
+    if !min.is_finite() || !max.is_finite() || min >= max {
+        return Err(RouteSyncError::InvalidInput("Temperature range min must be below max".to_string()));
+    }
+    Ok(())
+}
+
+// Helper function to enforce the configured list sizes and entry lengths
+fn check_list_limits(ingredients: &[String], certifications: &[String]) -> Result<(), RouteSyncError> {
+    let limits = LIMITS.with_borrow(|limits| limits.clone());
+    if ingredients.len() as u64 > limits.max_ingredients {
+        return Err(RouteSyncError::InvalidInput("Too many ingredients".to_string()));
+    }
+    if certifications.len() as u64 > limits.max_certifications {
+        return Err(RouteSyncError::InvalidInput("Too many certifications".to_string()));
+    }
+    check_field_lengths(ingredients.iter().chain(certifications))
+}
+
+// Helper function to reject any string longer than the configured field length
+fn check_field_lengths<'a>(fields: impl IntoIterator<Item = &'a String>) -> Result<(), RouteSyncError> {
+    let max_len = LIMITS.with_borrow(|limits| limits.max_field_len);
+    if fields.into_iter().any(|field| field.chars().count() as u64 > max_len) {
+        return Err(RouteSyncError::InvalidInput("Field too long".to_string()));
+    }
+    Ok(())
+}
+
+// Helper function to trim entries and drop case-insensitive duplicates,
+// keeping the first-seen casing
+fn dedupe_entries(entries: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    entries.into_iter()
+        .map(|entry| entry.trim().to_string())
+        .filter(|entry| seen.insert(entry.to_lowercase()))
+        .collect()
+}
+
+// Helper function to look up a product by manufacturer (case-insensitive) and batch number
+fn find_batch(manufacturer: &str, batch_number: &str) -> Option<Product> {
+    let manufacturer = manufacturer.trim().to_lowercase();
+    let batch_number = batch_number.trim();
+    PRODUCTS.with_borrow(|products| {
+        products.values()
+            .find(|product| {
+                product.manufacturer.trim().to_lowercase() == manufacturer
+                    && product.batch_number.trim() == batch_number
+            })
+            .cloned()
+    })
+}
+
+// Helper function to store a new product and its trace, seeded with a genesis Production event
+fn insert_product(input: ProductInput, owner: Principal) -> Result<String, RouteSyncError> {
+    validate_product_input(&input)?;
+    if find_batch(&input.manufacturer, &input.batch_number).is_some() {
+        return Err(RouteSyncError::DuplicateBatch);
+    }
+
+    let production_date = get_current_timestamp();
+    if input.expiry_date < production_date {
+        return Err(RouteSyncError::InvalidInput("Expiry date cannot be earlier than production date".to_string()));
+    }
+
+    let product_id = generate_id(PRODUCT_ID_PREFIX);
+    let genesis = SupplyChainEvent {
+        id: generate_id(EVENT_ID_PREFIX),
+        product_id: product_id.clone(),
+        event_type: EventType::Production,
+        location: input.location,
+        timestamp: production_date,
+        actor_id: String::new(),
+        actor: input.manufacturer.clone(),
+        actor_role: ParticipantRole::Manufacturer,
+        details: "Product created".to_string(),
+        coordinates: None,
+        temperature: None,
+        humidity: None,
+        prev_hash: String::new(),
+        hash: String::new(),
+        signature: Vec::new(),
+        signer_public_key: String::new(),
+        signer_id: String::new(),
+        distance_km: None,
+        transport_mode: None,
+        custody_to: None,
+        amends: None,
+        superseded: false,
+        documents: Vec::new(),
+        shipment_id: None,
+        created_by: owner,
+        tags: Vec::new(),
+        trace_index: 0,
+    };
+    let product = Product {
+        id: product_id.clone(),
+        name: input.name,
+        description: input.description,
+        manufacturer: input.manufacturer,
+        batch_number: input.batch_number,
+        production_date,
+        expiry_date: input.expiry_date,
+        ingredients: dedupe_entries(input.ingredients),
+        certifications: dedupe_entries(input.certifications),
+        allergens: input.allergens,
+        owner,
+        recalled: false,
+        recall_reason: None,
+        archived: false,
+        temp_range: input.temp_range,
+        components: Vec::new(),
+    };
+
+    PRODUCTS.with_borrow_mut(|products| {
+        products.insert(product_id.clone(), product);
+        // Debug: Log product creation
+        system::print(format!("Product created with ID: {}, total products: {}", product_id, products.len()));
+    });
+    record_change(&product_id, ChangeKind::ProductCreated);
+
+    // Create initial trace
+    let trace = SupplyChainTrace {
+        product_id: product_id.clone(),
+        events: Vec::new(),
+        created_at: get_current_timestamp(),
+        last_updated: get_current_timestamp(),
+        current_custodian: None,
+        finalized: false,
+    };
+
+    TRACES.with_borrow_mut(|traces| {
+        traces.insert(product_id.clone(), trace);
+        // Debug: Log trace creation
+        system::print(format!("Trace created for product: {}, total traces: {}", product_id, traces.len()));
+    });
+    append_event(genesis)?;
+
+    Ok(product_id)
+}
+
+// Helper function to reject product and event creation by the anonymous principal
+fn authenticated_caller() -> Result<Principal, RouteSyncError> {
+    let owner = system::caller();
+    if owner == Principal::anonymous() {
+        return Err(RouteSyncError::Unauthorized);
+    }
+    Ok(owner)
+}
+
+// Product management functions
+#[update]
+#[allow(clippy::too_many_arguments)]
+fn create_product(
+    name: String,
+    description: String,
+    manufacturer: String,
+    batch_number: String,
+    ingredients: Vec<String>,
+    certifications: Vec<String>,
+    expiry_date: u64,
+    allergens: Vec<String>,
+    location: String,
+    idempotency_key: Option<String>,
+    temp_range: Option<(f64, f64)>,
+) -> Result<String, RouteSyncError> {
+    let owner = authenticated_caller()?;
+
+    // A retried call with a key this owner already used returns the original product
+    let idempotency_key = idempotency_key.map(|key| (owner, key));
+    if let Some(key) = &idempotency_key {
+        let existing = IDEMPOTENCY_KEYS.with_borrow(|keys| keys.get(key).cloned())
+            .filter(|product_id| PRODUCTS.with_borrow(|products| products.contains_key(product_id)));
+        if let Some(product_id) = existing {
+            return Ok(product_id);
+        }
+    }
+
+    let input = ProductInput {
+        name,
+        description,
+        manufacturer,
+        batch_number,
+        ingredients,
+        certifications,
+        expiry_date,
+        allergens,
+        location,
+        temp_range,
+    };
+
+    let product_id = insert_product(input, owner)?;
+    if let Some(key) = idempotency_key {
+        IDEMPOTENCY_KEYS.with_borrow_mut(|keys| keys.insert(key, product_id.clone()));
+    }
+    Ok(product_id)
+}
+
+// Create many products in one call; invalid entries get an empty id
+#[update]
+fn create_products_batch(products: Vec<ProductInput>) -> Vec<String> {
+    let owner = authenticated_caller()
+        .unwrap_or_else(|_| system::trap("Anonymous principal cannot create products"));
+
+    products.into_iter()
+        .map(|input| insert_product(input, owner).unwrap_or_default())
+        .collect()
+}
+
+// Create a new batch of an existing product. The clone keeps the source's shelf life,
+// allergens and production location and starts its own trace.
+#[update]
+fn clone_product(source_id: String, new_batch_number: String) -> Result<String, RouteSyncError> {
+    ensure_owner(&source_id)?;
+    let owner = authenticated_caller()?;
+    let source = PRODUCTS.with_borrow(|products| products.get(&source_id).cloned())
+        .ok_or(RouteSyncError::ProductNotFound)?;
+    let location = TRACES.with_borrow(|traces| {
+        traces.get(&source_id)
+            .and_then(|trace| trace.events.first())
+            .map(|genesis| genesis.location.clone())
+            .unwrap_or_default()
+    });
+
+    let shelf_life = source.expiry_date.saturating_sub(source.production_date);
+    let input = ProductInput {
+        name: source.name,
+        description: source.description,
+        manufacturer: source.manufacturer,
+        batch_number: new_batch_number,
+        ingredients: source.ingredients,
+        certifications: source.certifications,
+        expiry_date: get_current_timestamp().saturating_add(shelf_life),
+        allergens: source.allergens,
+        location,
+        temp_range: source.temp_range,
+    };
+
+    insert_product(input, owner)
+}
+
+#[update]
+fn set_temp_range(product_id: String, min: f64, max: f64) -> Result<(), RouteSyncError> {
+    ensure_owner(&product_id)?;
+    validate_temp_range(min, max)?;
+
+    PRODUCTS.with_borrow_mut(|products| {
+        if let Some(product) = products.get_mut(&product_id) {
+            product.temp_range = Some((min, max));
+        }
+    });
+    record_change(&product_id, ChangeKind::ProductUpdated);
+    Ok(())
+}
+
+// Replace the components a product is assembled from, rejecting unknown ids and cycles
+#[update]
+fn set_components(product_id: String, components: Vec<String>) -> Result<(), RouteSyncError> {
+    ensure_owner(&product_id)?;
+    let components = dedupe_entries(components);
+
+    PRODUCTS.with_borrow_mut(|products| {
+        for component_id in &components {
+            if !products.contains_key(component_id) {
+                return Err(RouteSyncError::ProductNotFound);
             }
-        } else {
-            Err("Traces not initialized".to_string())
+            if reaches_component(products, component_id, &product_id) {
+                return Err(RouteSyncError::InvalidInput("Cyclic component reference".to_string()));
+            }
+        }
+        if let Some(product) = products.get_mut(&product_id) {
+            product.components = components;
+        }
+        Ok(())
+    })?;
+    record_change(&product_id, ChangeKind::ProductUpdated);
+    Ok(())
+}
+
+#[update]
+fn update_product(
+    product_id: String,
+    name: Option<String>,
+    description: Option<String>,
+    ingredients: Option<Vec<String>>,
+    certifications: Option<Vec<String>>,
+) -> Result<Product, RouteSyncError> {
+    ensure_owner(&product_id)?;
+    if let Some(name) = &name {
+        validate_product_name(name)?;
+    }
+    if let Some(ingredients) = &ingredients {
+        validate_ingredients(ingredients)?;
+    }
+    check_field_lengths(name.iter().chain(&description))?;
+    check_list_limits(
+        ingredients.as_deref().unwrap_or_default(),
+        certifications.as_deref().unwrap_or_default(),
+    )?;
+
+    PRODUCTS.with_borrow_mut(|products| {
+        let product = products.get_mut(&product_id)
+            .ok_or(RouteSyncError::ProductNotFound)?;
+
+        // Only overwrite the fields that were provided
+        if let Some(name) = name {
+            product.name = name;
+        }
+        if let Some(description) = description {
+            product.description = description;
+        }
+        if let Some(ingredients) = ingredients {
+            product.ingredients = dedupe_entries(ingredients);
+        }
+        if let Some(certifications) = certifications {
+            product.certifications = dedupe_entries(certifications);
+        }
+        Ok(product.clone())
+    })
+    .inspect(|_| record_change(&product_id, ChangeKind::ProductUpdated))
+}
+
+#[update]
+fn add_certification(product_id: String, cert: String) -> Result<(), RouteSyncError> {
+    ensure_owner(&product_id)?;
+
+    let cert = cert.trim().to_string();
+    if cert.is_empty() {
+        return Err(RouteSyncError::InvalidInput("Certification cannot be empty".to_string()));
+    }
+
+    PRODUCTS.with_borrow_mut(|products| {
+        if let Some(product) = products.get_mut(&product_id) {
+            // Skip certifications the product already carries
+            if product.certifications.iter().any(|existing| existing.to_lowercase() == cert.to_lowercase()) {
+                return Ok(());
+            }
+            let mut certifications = product.certifications.clone();
+            certifications.push(cert);
+            check_list_limits(&[], &certifications)?;
+            product.certifications = certifications;
+        }
+        Ok(())
+    })?;
+    record_change(&product_id, ChangeKind::ProductUpdated);
+    Ok(())
+}
+
+#[update]
+fn archive_product(product_id: String) -> Result<(), RouteSyncError> {
+    set_product_archived(&product_id, true)
+}
+
+#[update]
+fn unarchive_product(product_id: String) -> Result<(), RouteSyncError> {
+    set_product_archived(&product_id, false)
+}
+
+fn set_product_archived(product_id: &str, archived: bool) -> Result<(), RouteSyncError> {
+    ensure_owner(product_id)?;
+
+    PRODUCTS.with_borrow_mut(|products| {
+        if let Some(product) = products.get_mut(product_id) {
+            product.archived = archived;
         }
+    });
+    record_change(product_id, ChangeKind::ProductUpdated);
+    Ok(())
+}
+
+// Hand a product over to another principal (current owner only)
+#[update]
+fn transfer_ownership(product_id: String, new_owner: Principal) -> Result<(), RouteSyncError> {
+    ensure_owner(&product_id)?;
+    if new_owner == Principal::anonymous() {
+        return Err(RouteSyncError::InvalidInput("Cannot transfer ownership to the anonymous principal".to_string()));
     }
+
+    PRODUCTS.with_borrow_mut(|products| {
+        if let Some(product) = products.get_mut(&product_id) {
+            product.owner = new_owner;
+        }
+    });
+    record_change(&product_id, ChangeKind::ProductUpdated);
+    Ok(())
+}
+
+#[update]
+fn delete_product(product_id: String) -> Result<(), RouteSyncError> {
+    ensure_owner(&product_id)?;
+
+    // Remove the product, its trace and every event recorded against it
+    PRODUCTS.with_borrow_mut(|products| {
+        products.remove(&product_id);
+    });
+    TRACES.with_borrow_mut(|traces| {
+        traces.remove(&product_id);
+    });
+    EVENTS.with_borrow_mut(|events| {
+        events.retain(|_, event| event.product_id != product_id);
+    });
+
+    record_change(&product_id, ChangeKind::ProductDeleted);
+    Ok(())
+}
+
+#[update]
+fn recall_product(product_id: String, reason: String) -> Result<(), RouteSyncError> {
+    ensure_owner(&product_id)?;
+
+    PRODUCTS.with_borrow_mut(|products| {
+        if let Some(product) = products.get_mut(&product_id) {
+            product.recalled = true;
+            product.recall_reason = Some(reason);
+        }
+    });
+    record_change(&product_id, ChangeKind::ProductUpdated);
+    Ok(())
 }
 
-// Note: The canister interface is defined in supply_chain.did 
\ No newline at end of file
+#[update]
+#[allow(clippy::too_many_arguments)]
+fn add_supply_chain_event(
+    product_id: String,
+    event_type: EventType,
+    location: String,
+    actor_id: String,
+    details: String,
+    coordinates: Option<(f64, f64)>,
+    temperature: Option<f64>,
+    humidity: Option<f64>,
+    signature: Vec<u8>,
+    distance_km: Option<f64>,
+    transport_mode: Option<TransportMode>,
+    documents: Vec<DocumentRef>,
+    shipment_id: Option<String>,
+    tags: Vec<String>,
+) -> Result<String, RouteSyncError> {
+    // Verify product exists and belongs to the caller
+    ensure_owner(&product_id)?;
+    check_rate_limit(&actor_id)?;
+
+    let input = EventInput {
+        event_type,
+        location,
+        actor_id,
+        details,
+        coordinates,
+        temperature,
+        humidity,
+        signature,
+        distance_km,
+        transport_mode,
+        documents,
+        shipment_id,
+        tags,
+    };
+    let event = build_event(&product_id, input)?;
+    let actor_id = event.actor_id.clone();
+    let event_id = append_event(event)?;
+    record_rate_usage(&actor_id);
+    Ok(event_id)
+}
+
+// Insert many events for one product. Events that fail validation are skipped and
+// their indices reported through PartialBatchFailure alongside the inserted ids.
+#[update]
+fn add_events_batch(product_id: String, events: Vec<EventInput>) -> Result<Vec<String>, RouteSyncError> {
+    ensure_owner(&product_id)?;
+
+    let mut inserted = Vec::new();
+    let mut failed = Vec::new();
+    for (index, input) in events.into_iter().enumerate() {
+        let actor_id = input.actor_id.clone();
+        let result = check_rate_limit(&actor_id)
+            .and_then(|_| build_event(&product_id, input))
+            .and_then(chain_event);
+        match result {
+            Ok(event_id) => {
+                record_rate_usage(&actor_id);
+                inserted.push(event_id);
+            }
+            Err(_) => failed.push(index as u64),
+        }
+    }
+    touch_trace(&product_id);
+
+    if failed.is_empty() {
+        Ok(inserted)
+    } else {
+        Err(RouteSyncError::PartialBatchFailure { inserted, failed })
+    }
+}
+
+// Correct an event by appending an amendment rather than editing history
+#[update]
+fn amend_event(event_id: String, new_details: String) -> Result<(), RouteSyncError> {
+    let original = EVENTS.with_borrow(|events| events.get(&event_id).cloned())
+        .ok_or(RouteSyncError::EventNotFound)?;
+    ensure_owner(&original.product_id)?;
+    if original.superseded {
+        return Err(RouteSyncError::InvalidInput("Event already superseded".to_string()));
+    }
+    // The amendment is attributed to the original actor and counts against their limit
+    check_rate_limit(&original.actor_id)?;
+
+    let amendment = SupplyChainEvent {
+        id: generate_id(EVENT_ID_PREFIX),
+        timestamp: get_current_timestamp(),
+        details: new_details,
+        signature: Vec::new(),
+        signer_public_key: String::new(),
+        signer_id: String::new(),
+        custody_to: None,
+        amends: Some(event_id.clone()),
+        created_by: system::caller(),
+        ..original.clone()
+    };
+    append_event(amendment)?;
+    record_rate_usage(&original.actor_id);
+
+    modify_event(&original.product_id, &event_id, |event| event.superseded = true);
+    Ok(())
+}
+
+// Scrub personal data from an event for erasure requests (admin only). Timestamp
+// and event type are kept so the trace length and ordering stay intact. Amendments
+// copy the actor of the event they correct, so the whole amendment family is
+// scrubbed. Signatures no longer match the scrubbed content and are dropped. The
+// chain must verify up to the first scrubbed event and is re-hashed from there:
+// verify_event_chain keeps passing, but hashes published before the redaction
+// will no longer match from that event on.
+#[update]
+fn redact_event(event_id: String) -> Result<(), RouteSyncError> {
+    ensure_admin()?;
+    let product_id = EVENTS.with_borrow(|events| events.get(&event_id).map(|event| event.product_id.clone()))
+        .ok_or(RouteSyncError::EventNotFound)?;
+    let events = TRACES.with_borrow(|traces| traces.get(&product_id).map(|trace| trace.events.clone()))
+        .ok_or(RouteSyncError::TraceNotFound)?;
+
+    // Collect the event plus every event linked to it through amends, either way
+    let mut family = HashSet::from([event_id.clone()]);
+    loop {
+        let linked: Vec<String> = events.iter()
+            .filter(|event| !family.contains(&event.id))
+            .filter(|event| {
+                event.amends.as_ref().is_some_and(|amends| family.contains(amends))
+                    || events.iter().any(|member| {
+                        family.contains(&member.id) && member.amends.as_ref() == Some(&event.id)
+                    })
+            })
+            .map(|event| event.id.clone())
+            .collect();
+        if linked.is_empty() {
+            break;
+        }
+        family.extend(linked);
+    }
+    let start = events.iter()
+        .position(|event| family.contains(&event.id))
+        .ok_or(RouteSyncError::EventNotFound)?;
+    ensure_chain_intact_before(&product_id, start)?;
+
+    for member in &family {
+        modify_event(&product_id, member, |event| {
+            event.actor = "[redacted]".to_string();
+            event.details = String::new();
+            event.coordinates = None;
+            event.signature = Vec::new();
+        });
+    }
+    rehash_trace_from(&product_id, start);
+    Ok(())
+}
+
+// Remove the most recent event of a trace, restricted to whoever submitted it while
+// they still own the product, or an admin. The genesis event is never removed.
+// Undoing an amendment restores the event it superseded, and undoing a custody
+// transfer hands custody back to the previous holder.
+#[update]
+fn undo_last_event(product_id: String) -> Result<SupplyChainEvent, RouteSyncError> {
+    if !PRODUCTS.with_borrow(|products| products.contains_key(&product_id)) {
+        return Err(RouteSyncError::ProductNotFound);
+    }
+    let last = TRACES.with_borrow(|traces| {
+        let trace = traces.get(&product_id).ok_or(RouteSyncError::TraceNotFound)?;
+        if trace.finalized {
+            return Err(RouteSyncError::TraceFinalized);
+        }
+        match trace.events.as_slice() {
+            [] => Err(RouteSyncError::NoEvents),
+            [_] => Err(RouteSyncError::InvalidInput("The genesis event cannot be undone".to_string())),
+            [.., last] => Ok(last.clone()),
+        }
+    })?;
+    if last.created_by != system::caller() || ensure_owner(&product_id).is_err() {
+        ensure_admin()?;
+    }
+
+    EVENTS.with_borrow_mut(|events| events.remove(&last.id));
+    TRACES.with_borrow_mut(|traces| {
+        if let Some(trace) = traces.get_mut(&product_id) {
+            trace.events.pop();
+            // The sender of a transfer was its holder, so custody goes back to them
+            if last.custody_to.is_some() {
+                trace.current_custodian = Some(last.actor_id.clone());
+            }
+        }
+    });
+    if let Some(original_id) = &last.amends {
+        modify_event(&product_id, original_id, |event| event.superseded = false);
+    }
+    touch_trace(&product_id);
+    record_change(&product_id, ChangeKind::EventRemoved);
+    Ok(last)
+}
+
+// Close a trace once the product reaches a consumer (owner only)
+#[update]
+fn finalize_trace(product_id: String) -> Result<(), RouteSyncError> {
+    ensure_owner(&product_id)?;
+    set_trace_finalized(&product_id, true)
+}
+
+// Reopen a finalized trace (admin only)
+#[update]
+fn reopen_trace(product_id: String) -> Result<(), RouteSyncError> {
+    ensure_admin()?;
+    set_trace_finalized(&product_id, false)
+}
+
+fn set_trace_finalized(product_id: &str, finalized: bool) -> Result<(), RouteSyncError> {
+    TRACES.with_borrow_mut(|traces| {
+        let trace = traces.get_mut(product_id).ok_or(RouteSyncError::TraceNotFound)?;
+        trace.finalized = finalized;
+        trace.last_updated = get_current_timestamp();
+        Ok(())
+    })?;
+    record_change(product_id, ChangeKind::ProductUpdated);
+    Ok(())
+}
+
+// Helper function to build the shipping event recording a custody hand-over; the
+// signed details name the recipient
+fn custody_transfer_input(from: &str, to: &str, signature: Vec<u8>) -> Result<EventInput, RouteSyncError> {
+    let (sender, recipient) = PARTICIPANTS.with_borrow(|participants| {
+        (participants.get(from).cloned(), participants.get(to).cloned())
+    });
+    let sender = sender.ok_or(RouteSyncError::ParticipantNotFound)?;
+    let recipient = recipient.ok_or(RouteSyncError::ParticipantNotFound)?;
+
+    Ok(EventInput {
+        event_type: EventType::Shipping,
+        location: sender.location,
+        actor_id: from.to_string(),
+        details: format!("Custody transferred to {} ({})", recipient.name, to),
+        coordinates: None,
+        temperature: None,
+        humidity: None,
+        signature,
+        distance_km: None,
+        transport_mode: None,
+        documents: Vec::new(),
+        shipment_id: None,
+        tags: Vec::new(),
+    })
+}
+
+// Bytes the sender must sign to hand custody of a product to `to`
+#[query]
+fn get_custody_signing_payload(product_id: String, from: String, to: String) -> Result<Vec<u8>, RouteSyncError> {
+    get_signing_payload(product_id, custody_transfer_input(&from, &to, Vec::new())?)
+}
+
+// Name the participant first holding a product's goods (owner only). Custody can
+// only be assigned once; after that it moves through transfer_custody.
+#[update]
+fn assign_custodian(product_id: String, custodian_id: String) -> Result<(), RouteSyncError> {
+    ensure_owner(&product_id)?;
+    get_participant(custodian_id.clone())?;
+
+    TRACES.with_borrow_mut(|traces| {
+        let trace = traces.get_mut(&product_id).ok_or(RouteSyncError::TraceNotFound)?;
+        if trace.finalized {
+            return Err(RouteSyncError::TraceFinalized);
+        }
+        if trace.current_custodian.is_some() {
+            return Err(RouteSyncError::CustodyMismatch);
+        }
+        trace.current_custodian = Some(custodian_id);
+        trace.last_updated = get_current_timestamp();
+        Ok(())
+    })?;
+    record_change(&product_id, ChangeKind::ProductUpdated);
+    Ok(())
+}
+
+#[update]
+fn transfer_custody(product_id: String, from: String, to: String, signature: Vec<u8>) -> Result<(), RouteSyncError> {
+    ensure_owner(&product_id)?;
+
+    let current_custodian = TRACES.with_borrow(|traces| {
+        traces.get(&product_id).map(|trace| trace.current_custodian.clone())
+    })
+    .ok_or(RouteSyncError::TraceNotFound)?;
+
+    // Custody is assigned explicitly first, after which only its holder hands it on
+    if current_custodian.as_deref() != Some(from.as_str()) {
+        return Err(RouteSyncError::CustodyMismatch);
+    }
+    check_rate_limit(&from)?;
+
+    // The hand-over is a shipping event signed by the sender, so it runs the same
+    // actor checks as any other event
+    let input = custody_transfer_input(&from, &to, signature)?;
+    let event = SupplyChainEvent {
+        custody_to: Some(to.clone()),
+        ..build_event(&product_id, input)?
+    };
+    append_event(event)?;
+    record_rate_usage(&from);
+
+    TRACES.with_borrow_mut(|traces| {
+        if let Some(trace) = traces.get_mut(&product_id) {
+            trace.current_custodian = Some(to);
+        }
+    });
+
+    Ok(())
+}
+
+#[update]
+fn register_participant(
+    name: String,
+    role: ParticipantRole,
+    location: String,
+    public_key: String,
+) -> Result<String, RouteSyncError> {
+    insert_participant(ParticipantInput {
+        name,
+        role,
+        location,
+        public_key,
+    })
+}
+
+// Register many participants in one call; rejected entries get an empty id
+#[update]
+fn register_participants_batch(participants: Vec<ParticipantInput>) -> Vec<String> {
+    participants.into_iter()
+        .map(|input| insert_participant(input).unwrap_or_default())
+        .collect()
+}
+
+// Helper function to reject a blank participant name
+fn validate_participant_name(name: &str) -> Result<(), RouteSyncError> {
+    if name.trim().is_empty() {
+        return Err(RouteSyncError::InvalidInput("Participant name cannot be empty".to_string()));
+    }
+    Ok(())
+}
+
+// Helper function to reject anything but a hex-encoded 32-byte Ed25519 public key
+fn validate_public_key(public_key: &str) -> Result<(), RouteSyncError> {
+    let valid = hex::decode(public_key).ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes.as_slice()).ok())
+        .is_some_and(|bytes| VerifyingKey::from_bytes(&bytes).is_ok());
+    if !valid {
+        return Err(RouteSyncError::InvalidInput("Invalid public key".to_string()));
+    }
+    Ok(())
+}
+
+// Helper function to store a new, unverified participant and return its id
+fn insert_participant(input: ParticipantInput) -> Result<String, RouteSyncError> {
+    validate_participant_name(&input.name)?;
+    validate_public_key(&input.public_key)?;
+    warn_if_low_cycles();
+    let participant_id = generate_id(PARTICIPANT_ID_PREFIX);
+    let participant = Participant {
+        id: participant_id.clone(),
+        name: input.name,
+        role: input.role,
+        location: input.location,
+        public_key: input.public_key,
+        is_verified: false,
+    };
+
+    PARTICIPANTS.with_borrow_mut(|participants| {
+        participants.insert(participant_id.clone(), participant);
+    });
+    Ok(participant_id)
+}
+
+// Participant verification functions (admin only)
+#[update]
+fn verify_participant(participant_id: String) -> Result<Participant, RouteSyncError> {
+    set_participant_verified(&participant_id, true)
+}
+
+#[update]
+fn revoke_participant(participant_id: String) -> Result<Participant, RouteSyncError> {
+    set_participant_verified(&participant_id, false)
+}
+
+fn set_participant_verified(participant_id: &str, is_verified: bool) -> Result<Participant, RouteSyncError> {
+    ensure_admin()?;
+
+    PARTICIPANTS.with_borrow_mut(|participants| {
+        let participant = participants.get_mut(participant_id)
+            .ok_or(RouteSyncError::ParticipantNotFound)?;
+        participant.is_verified = is_verified;
+        Ok(participant.clone())
+    })
+}
+
+// Merge a duplicate registration into the participant that is kept (admin only).
+// Reassigned events still verify, since their signature covers signer_id, which is
+// left alone. Affected traces are re-chained from their first reassigned event.
+#[update]
+fn merge_participants(keep_id: String, remove_id: String) -> Result<(), RouteSyncError> {
+    ensure_admin()?;
+    if keep_id == remove_id {
+        return Err(RouteSyncError::InvalidInput("Cannot merge a participant into itself".to_string()));
+    }
+
+    let (keep, remove) = PARTICIPANTS.with_borrow(|participants| {
+        (participants.get(&keep_id).cloned(), participants.get(&remove_id).cloned())
+    });
+    let keep = keep.ok_or(RouteSyncError::ParticipantNotFound)?;
+    let remove = remove.ok_or(RouteSyncError::ParticipantNotFound)?;
+    if keep.role != remove.role {
+        return Err(RouteSyncError::InvalidInput("Participants have different roles".to_string()));
+    }
+
+    // Find the first event of every trace that references the removed participant
+    let references = |event: &SupplyChainEvent| {
+        event.actor_id == remove_id || event.custody_to.as_ref() == Some(&remove_id)
+    };
+    let affected: Vec<(String, usize)> = TRACES.with_borrow(|traces| {
+        traces.values()
+            .filter_map(|trace| {
+                trace.events.iter()
+                    .position(references)
+                    .map(|index| (trace.product_id.clone(), index))
+            })
+            .collect()
+    });
+    for (product_id, index) in &affected {
+        ensure_chain_intact_before(product_id, *index)?;
+    }
+
+    // Reassign events and custody held by the removed participant
+    let reassign = |event: &mut SupplyChainEvent| {
+        if event.actor_id == remove_id {
+            event.actor_id = keep_id.clone();
+            event.actor = keep.name.clone();
+        }
+        if event.custody_to.as_ref() == Some(&remove_id) {
+            event.custody_to = Some(keep_id.clone());
+        }
+    };
+    EVENTS.with_borrow_mut(|events| events.values_mut().for_each(reassign));
+    TRACES.with_borrow_mut(|traces| {
+        for trace in traces.values_mut() {
+            trace.events.iter_mut().for_each(reassign);
+            if trace.current_custodian.as_ref() == Some(&remove_id) {
+                trace.current_custodian = Some(keep_id.clone());
+            }
+        }
+    });
+    for (product_id, index) in &affected {
+        rehash_trace_from(product_id, *index);
+        record_change(product_id, ChangeKind::EventUpdated);
+    }
+
+    PARTICIPANTS.with_borrow_mut(|participants| {
+        participants.remove(&remove_id);
+    });
+    Ok(())
+}
+
+// Edit a participant's contact info (admin only). A new public_key only applies to
+// future events; existing events keep the key they were verified against.
+#[update]
+fn update_participant(
+    participant_id: String,
+    name: Option<String>,
+    location: Option<String>,
+    public_key: Option<String>,
+) -> Result<Participant, RouteSyncError> {
+    ensure_admin()?;
+    if let Some(name) = &name {
+        validate_participant_name(name)?;
+    }
+    if let Some(public_key) = &public_key {
+        validate_public_key(public_key)?;
+    }
+
+    PARTICIPANTS.with_borrow_mut(|participants| {
+        let participant = participants.get_mut(&participant_id)
+            .ok_or(RouteSyncError::ParticipantNotFound)?;
+
+        // Only overwrite the fields that were provided
+        if let Some(name) = name {
+            participant.name = name;
+        }
+        if let Some(location) = location {
+            participant.location = location;
+        }
+        if let Some(public_key) = public_key {
+            participant.public_key = public_key;
+        }
+        Ok(participant.clone())
+    })
+}
+
+// Grant admin rights to another principal (admin only)
+#[update]
+fn add_admin(p: Principal) -> Result<(), RouteSyncError> {
+    ensure_admin()?;
+    if p == Principal::anonymous() {
+        return Err(RouteSyncError::InvalidInput("Anonymous principal cannot be an admin".to_string()));
+    }
+    ADMINS.with_borrow_mut(|admins| admins.insert(p));
+    Ok(())
+}
+
+// Revoke admin rights (admin only). At least one admin always remains.
+#[update]
+fn remove_admin(p: Principal) -> Result<(), RouteSyncError> {
+    ensure_admin()?;
+    ADMINS.with_borrow_mut(|admins| {
+        if admins.contains(&p) && admins.len() == 1 {
+            return Err(RouteSyncError::LastAdmin);
+        }
+        admins.remove(&p);
+        Ok(())
+    })
+}
+
+#[query]
+fn list_admins() -> Vec<Principal> {
+    let mut admins: Vec<Principal> = ADMINS.with_borrow(|admins| admins.iter().cloned().collect());
+    admins.sort();
+    admins
+}
+
+// Replace the per-participant event rate limit (admin only)
+#[update]
+fn set_rate_limit(rate_limit: RateLimit) -> Result<(), RouteSyncError> {
+    ensure_admin()?;
+    if rate_limit.window_secs == 0 {
+        return Err(RouteSyncError::InvalidInput("Rate limit window cannot be zero".to_string()));
+    }
+    RATE_LIMIT.set(rate_limit);
+    Ok(())
+}
+
+// Current rate limit usage of a participant (admin only)
+#[query]
+fn get_rate_limit_status(participant_id: String) -> Result<RateLimitStatus, RouteSyncError> {
+    ensure_admin()?;
+    let rate_limit = RATE_LIMIT.with_borrow(|rate_limit| rate_limit.clone());
+    Ok(RateLimitStatus {
+        used: events_in_rate_window(&participant_id),
+        max_events: rate_limit.max_events,
+        window_secs: rate_limit.window_secs,
+    })
+}
+
+// Set the cycle balance below which mutating calls log a warning (admin only)
+#[update]
+fn set_low_cycle_threshold(t: u64) -> Result<(), RouteSyncError> {
+    ensure_admin()?;
+    LOW_CYCLE_THRESHOLD.set(t);
+    Ok(())
+}
+
+#[query]
+fn cycle_balance() -> u64 {
+    system::canister_balance()
+}
+
+// Toggle whether events may only be logged by verified participants (admin only)
+#[update]
+fn set_require_verified_actors(required: bool) -> Result<(), RouteSyncError> {
+    ensure_admin()?;
+    REQUIRE_VERIFIED_ACTORS.set(required);
+    Ok(())
+}
+
+// Replace the product field limits (admin only)
+#[update]
+fn set_limits(limits: Limits) -> Result<(), RouteSyncError> {
+    ensure_admin()?;
+    LIMITS.set(limits);
+    Ok(())
+}
+
+#[query]
+fn get_limits() -> Limits {
+    LIMITS.with_borrow(|limits| limits.clone())
+}
+
+// Wipe all products, traces, events and participants (admin only).
+// The id counter, changelog and configuration are kept so ids are never reused
+// and polling clients see every product as deleted.
+#[update]
+fn clear_all() {
+    if ensure_admin().is_err() {
+        system::trap("Unauthorized");
+    }
+
+    let product_ids: Vec<String> = PRODUCTS.with_borrow(|products| products.keys().cloned().collect());
+    PRODUCTS.with_borrow_mut(|products| products.clear());
+    TRACES.with_borrow_mut(|traces| traces.clear());
+    EVENTS.with_borrow_mut(|events| events.clear());
+    PARTICIPANTS.with_borrow_mut(|participants| participants.clear());
+    IDEMPOTENCY_KEYS.with_borrow_mut(|keys| keys.clear());
+
+    // Logged after clearing, so recording each deletion only drops its digest
+    for product_id in &product_ids {
+        record_change(product_id, ChangeKind::ProductDeleted);
+    }
+}
+
+// Cheap liveness check. The canister counts as initialized once init or
+// post_upgrade has run and seeded the admin set.
+#[query]
+fn health() -> HealthStatus {
+    let started_at = STARTED_AT.with_borrow(|started_at| *started_at);
+    HealthStatus {
+        initialized: started_at > 0 && ADMINS.with_borrow(|admins| !admins.is_empty()),
+        version: VERSION.to_string(),
+        uptime_seconds: get_current_timestamp().saturating_sub(started_at) / 1000,
+    }
+}
+
+// Test method to debug Candid interface
+#[update]
+fn test_simple() -> String {
+    "Hello from canister".to_string()
+}
+
+// Query functions
+#[query]
+fn get_product(product_id: String) -> Result<Product, RouteSyncError> {
+    PRODUCTS.with_borrow(|products| {
+        products.get(&product_id)
+            .cloned()
+            .ok_or(RouteSyncError::ProductNotFound)
+    })
+}
+
+#[query]
+fn get_product_owner(product_id: String) -> Result<Principal, RouteSyncError> {
+    PRODUCTS.with_borrow(|products| {
+        products.get(&product_id)
+            .map(|product| product.owner)
+            .ok_or(RouteSyncError::ProductNotFound)
+    })
+}
+
+// Payload is base64url("<id>|<batch_number>|<32-byte product digest>")
+#[query]
+fn get_qr_payload(product_id: String) -> Result<String, RouteSyncError> {
+    let product = PRODUCTS.with_borrow(|products| products.get(&product_id).cloned())
+        .ok_or(RouteSyncError::ProductNotFound)?;
+
+    let mut raw = format!("{}|{}|", product.id, product.batch_number).into_bytes();
+    raw.extend_from_slice(&product_digest(&product));
+
+    let payload = URL_SAFE_NO_PAD.encode(raw);
+    if payload.len() > MAX_QR_PAYLOAD_BYTES {
+        return Err(RouteSyncError::InvalidInput("Batch number too long for QR payload".to_string()));
+    }
+    Ok(payload)
+}
+
+#[query]
+fn get_supply_chain_trace(product_id: String) -> Option<SupplyChainTrace> {
+    TRACES.with_borrow(|traces| traces.get(&product_id).cloned())
+}
+
+#[query]
+fn get_component_tree(product_id: String) -> Result<ComponentTree, RouteSyncError> {
+    PRODUCTS.with_borrow(|products| {
+        let product = products.get(&product_id).ok_or(RouteSyncError::ProductNotFound)?;
+        Ok(build_component_tree(products, product, 0))
+    })
+}
+
+#[query]
+fn get_trace_summary(product_id: String) -> Result<TraceSummary, RouteSyncError> {
+    if !PRODUCTS.with_borrow(|products| products.contains_key(&product_id)) {
+        return Err(RouteSyncError::ProductNotFound);
+    }
+
+    TRACES.with_borrow(|traces| {
+        let trace = traces.get(&product_id).ok_or(RouteSyncError::TraceNotFound)?;
+        Ok(TraceSummary {
+            product_id: trace.product_id.clone(),
+            event_count: trace.events.len() as u64,
+            created_at: trace.created_at,
+            last_updated: trace.last_updated,
+            latest_event_type: trace.events.last().map(|event| event.event_type.clone()),
+        })
+    })
+}
+
+#[query]
+fn get_event(event_id: String) -> Result<SupplyChainEvent, RouteSyncError> {
+    EVENTS.with_borrow(|events| {
+        events.get(&event_id)
+            .cloned()
+            .ok_or(RouteSyncError::EventNotFound)
+    })
+}
+
+// Bytes an actor must sign to log `input` as the next event of a product
+#[query]
+fn get_signing_payload(product_id: String, input: EventInput) -> Result<Vec<u8>, RouteSyncError> {
+    if !TRACES.with_borrow(|traces| traces.contains_key(&product_id)) {
+        return Err(RouteSyncError::TraceNotFound);
+    }
+    Ok(event_signing_payload(&product_id, next_trace_index(&product_id), &input))
+}
+
+#[query]
+fn get_event_documents(event_id: String) -> Result<Vec<DocumentRef>, RouteSyncError> {
+    EVENTS.with_borrow(|events| {
+        events.get(&event_id)
+            .map(|event| event.documents.clone())
+            .ok_or(RouteSyncError::EventNotFound)
+    })
+}
+
+#[query]
+fn get_events_by_type(event_type: EventType) -> Vec<SupplyChainEvent> {
+    EVENTS.with_borrow(|events| {
+        let mut matching: Vec<SupplyChainEvent> = events.values()
+            .filter(|event| event.event_type == event_type)
+            .cloned()
+            .collect();
+        matching.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)));
+        matching
+    })
+}
+
+#[query]
+fn get_events_near(lat: f64, lon: f64, radius_km: f64) -> Vec<SupplyChainEvent> {
+    if !is_valid_coordinate(lat, lon) {
+        return Vec::new();
+    }
+
+    EVENTS.with_borrow(|events| {
+        let mut nearby: Vec<SupplyChainEvent> = events.values()
+            .filter(|event| {
+                event.coordinates
+                    .is_some_and(|coordinates| haversine_km((lat, lon), coordinates) <= radius_km)
+            })
+            .cloned()
+            .collect();
+        nearby.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)));
+        nearby
+    })
+}
+
+#[query]
+fn get_all_products() -> Vec<Product> {
+    PRODUCTS.with_borrow(|products| {
+        products.values()
+            .filter(|product| !product.archived)
+            .cloned()
+            .collect()
+    })
+}
+
+#[query]
+fn get_products_paged(offset: usize, limit: usize) -> Vec<Product> {
+    PRODUCTS.with_borrow(|products| {
+        // Sort so that pages are stable across calls
+        let mut sorted: Vec<&Product> = products.values()
+            .filter(|product| !product.archived)
+            .collect();
+        sorted.sort_by(|a, b| {
+            a.production_date.cmp(&b.production_date).then_with(|| a.id.cmp(&b.id))
+        });
+
+        sorted.into_iter()
+            .skip(offset)
+            .take(limit.min(MAX_PAGE_SIZE))
+            .cloned()
+            .collect()
+    })
+}
+
+// Products owned by the caller; the anonymous principal owns nothing
+#[query]
+fn get_my_products() -> Vec<Product> {
+    let caller = system::caller();
+    if caller == Principal::anonymous() {
+        return Vec::new();
+    }
+    get_products_by_owner(caller)
+}
+
+#[query]
+fn get_products_by_owner(owner: Principal) -> Vec<Product> {
+    PRODUCTS.with_borrow(|products| {
+        let mut owned: Vec<Product> = products.values()
+            .filter(|product| product.owner == owner)
+            .cloned()
+            .collect();
+        owned.sort_by(|a, b| {
+            a.production_date.cmp(&b.production_date).then_with(|| a.id.cmp(&b.id))
+        });
+        owned
+    })
+}
+
+// Distinct manufacturers of non-archived products, sorted alphabetically
+#[query]
+fn list_manufacturers() -> Vec<String> {
+    PRODUCTS.with_borrow(|products| {
+        let manufacturers: BTreeSet<String> = products.values()
+            .filter(|product| !product.archived)
+            .map(|product| product.manufacturer.clone())
+            .collect();
+        manufacturers.into_iter().collect()
+    })
+}
+
+#[query]
+fn get_products_by_manufacturer(manufacturer: String) -> Vec<Product> {
+    let manufacturer = manufacturer.trim().to_lowercase();
+    if manufacturer.is_empty() {
+        return Vec::new();
+    }
+
+    PRODUCTS.with_borrow(|products| {
+        let mut matching: Vec<Product> = products.values()
+            .filter(|product| product.manufacturer.trim().to_lowercase() == manufacturer)
+            .cloned()
+            .collect();
+        matching.sort_by(|a, b| {
+            a.production_date.cmp(&b.production_date).then_with(|| a.id.cmp(&b.id))
+        });
+        matching
+    })
+}
+
+#[query]
+fn get_expired_products(as_of: u64) -> Vec<Product> {
+    PRODUCTS.with_borrow(|products| {
+        products.values()
+            .filter(|product| product.expiry_date < as_of)
+            .cloned()
+            .collect()
+    })
+}
+
+#[query]
+fn is_product_expired(product_id: String) -> Result<bool, RouteSyncError> {
+    let now = get_current_timestamp();
+    PRODUCTS.with_borrow(|products| {
+        products.get(&product_id)
+            .map(|product| product.expiry_date < now)
+            .ok_or(RouteSyncError::ProductNotFound)
+    })
+}
+
+#[query]
+fn get_recalled_products() -> Vec<Product> {
+    PRODUCTS.with_borrow(|products| {
+        products.values()
+            .filter(|product| product.recalled)
+            .cloned()
+            .collect()
+    })
+}
+
+#[query]
+fn search_products(term: String) -> Vec<Product> {
+    let term = term.trim().to_lowercase();
+    if term.chars().count() < MIN_SEARCH_TERM_LEN {
+        return Vec::new();
+    }
+
+    PRODUCTS.with_borrow(|products| {
+        let mut matching: Vec<Product> = products.values()
+            .filter(|product| {
+                product.name.to_lowercase().contains(&term)
+                    || product.description.to_lowercase().contains(&term)
+            })
+            .cloned()
+            .collect();
+        matching.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+        matching
+    })
+}
+
+#[query]
+fn get_products_by_certification(certification: String) -> Vec<Product> {
+    let certification = certification.trim();
+    PRODUCTS.with_borrow(|products| {
+        products.values()
+            .filter(|product| {
+                product.certifications.iter().any(|cert| cert.trim().eq_ignore_ascii_case(certification))
+            })
+            .cloned()
+            .collect()
+    })
+}
+
+#[query]
+fn get_products_in_range(start: u64, end: u64) -> Vec<Product> {
+    if start > end {
+        return Vec::new();
+    }
+
+    PRODUCTS.with_borrow(|products| {
+        let mut matching: Vec<Product> = products.values()
+            .filter(|product| (start..=end).contains(&product.production_date))
+            .cloned()
+            .collect();
+        matching.sort_by(|a, b| {
+            a.production_date.cmp(&b.production_date).then_with(|| a.id.cmp(&b.id))
+        });
+        matching
+    })
+}
+
+#[query]
+fn find_product_by_batch(manufacturer: String, batch_number: String) -> Option<Product> {
+    find_batch(&manufacturer, &batch_number)
+}
+
+#[query]
+fn get_products_with_allergen(allergen: String) -> Vec<Product> {
+    let allergen = allergen.trim().to_lowercase();
+    PRODUCTS.with_borrow(|products| {
+        products.values()
+            .filter(|product| product.allergens.iter().any(|a| a.trim().to_lowercase() == allergen))
+            .cloned()
+            .collect()
+    })
+}
+
+#[query]
+fn contains_allergen(product_id: String, allergen: String) -> Result<bool, RouteSyncError> {
+    let allergen = allergen.trim().to_lowercase();
+    PRODUCTS.with_borrow(|products| {
+        products.get(&product_id)
+            .map(|product| product.allergens.iter().any(|a| a.trim().to_lowercase() == allergen))
+            .ok_or(RouteSyncError::ProductNotFound)
+    })
+}
+
+#[query]
+fn products_missing_event(required: EventType) -> Vec<Product> {
+    // Products without any trace are included as well
+    TRACES.with_borrow(|traces| {
+        PRODUCTS.with_borrow(|products| {
+            products.values()
+                .filter(|product| {
+                    !traces.get(&product.id)
+                        .is_some_and(|trace| trace.events.iter().any(|event| event.event_type == required))
+                })
+                .cloned()
+                .collect()
+        })
+    })
+}
+
+#[query]
+fn get_products_count() -> u64 {
+    // Matches the products returned by get_products_paged
+    PRODUCTS.with_borrow(|products| {
+        products.values().filter(|product| !product.archived).count() as u64
+    })
+}
+
+#[query]
+fn get_archived_products() -> Vec<Product> {
+    PRODUCTS.with_borrow(|products| {
+        products.values()
+            .filter(|product| product.archived)
+            .cloned()
+            .collect()
+    })
+}
+
+#[query]
+fn get_participants() -> Vec<Participant> {
+    PARTICIPANTS.with_borrow(|participants| participants.values().cloned().collect())
+}
+
+#[query]
+fn get_participant(participant_id: String) -> Result<Participant, RouteSyncError> {
+    PARTICIPANTS.with_borrow(|participants| {
+        participants.get(&participant_id)
+            .cloned()
+            .ok_or(RouteSyncError::ParticipantNotFound)
+    })
+}
+
+#[query]
+fn get_participants_by_role(role: ParticipantRole) -> Vec<Participant> {
+    PARTICIPANTS.with_borrow(|participants| {
+        let mut matching: Vec<Participant> = participants.values()
+            .filter(|participant| participant.role == role)
+            .cloned()
+            .collect();
+        matching.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+        matching
+    })
+}
+
+#[query]
+fn get_verified_participants() -> Vec<Participant> {
+    PARTICIPANTS.with_borrow(|participants| {
+        participants.values()
+            .filter(|participant| participant.is_verified)
+            .cloned()
+            .collect()
+    })
+}
+
+// Case-insensitive substring search over the resolved actor name
+#[query]
+fn find_events_by_actor(substring: String) -> Vec<SupplyChainEvent> {
+    let substring = substring.trim().to_lowercase();
+    if substring.chars().count() < MIN_SEARCH_TERM_LEN {
+        return Vec::new();
+    }
+
+    EVENTS.with_borrow(|events| {
+        let mut matching: Vec<SupplyChainEvent> = events.values()
+            .filter(|event| event.actor.to_lowercase().contains(&substring))
+            .cloned()
+            .collect();
+        matching.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)));
+        matching
+    })
+}
+
+// Events carrying the given tag, oldest first
+#[query]
+fn events_by_tag(tag: String) -> Vec<SupplyChainEvent> {
+    let tag = tag.trim().to_lowercase();
+    EVENTS.with_borrow(|events| {
+        let mut matching: Vec<SupplyChainEvent> = events.values()
+            .filter(|event| event.tags.contains(&tag))
+            .cloned()
+            .collect();
+        matching.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)));
+        matching
+    })
+}
+
+// Case-insensitive substring search over event locations
+#[query]
+fn events_by_location(substring: String) -> Vec<SupplyChainEvent> {
+    let substring = substring.trim().to_lowercase();
+    if substring.chars().count() < MIN_SEARCH_TERM_LEN {
+        return Vec::new();
+    }
+
+    EVENTS.with_borrow(|events| {
+        let mut matching: Vec<SupplyChainEvent> = events.values()
+            .filter(|event| event.location.to_lowercase().contains(&substring))
+            .cloned()
+            .collect();
+        matching.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)));
+        matching
+    })
+}
+
+// Distinct products with at least one event in the given shipment
+#[query]
+fn products_in_shipment(shipment_id: String) -> Vec<String> {
+    EVENTS.with_borrow(|events| {
+        let product_ids: BTreeSet<String> = events.values()
+            .filter(|event| event.shipment_id.as_ref() == Some(&shipment_id))
+            .map(|event| event.product_id.clone())
+            .collect();
+        product_ids.into_iter().collect()
+    })
+}
+
+// Trust score from 0 to 100, the sum of:
+//   40 if the participant is verified,
+//   1 per event logged, up to 30,
+//   30 x the share of products they touched whose trace is in order, has an
+//   intact hash chain and carries only valid signatures from them (0 if none).
+#[query]
+fn participant_reputation(participant_id: String) -> Result<u8, RouteSyncError> {
+    let participant = PARTICIPANTS.with_borrow(|participants| participants.get(&participant_id).cloned())
+        .ok_or(RouteSyncError::ParticipantNotFound)?;
+
+    let (events_logged, touched, clean) = TRACES.with_borrow(|traces| {
+        let mut events_logged = 0u64;
+        let (mut touched, mut clean) = (0u64, 0u64);
+        for trace in traces.values() {
+            let own: Vec<&SupplyChainEvent> = trace.events.iter()
+                .filter(|event| event.actor_id == participant_id)
+                .collect();
+            if own.is_empty() {
+                continue;
+            }
+            events_logged += own.len() as u64;
+            touched += 1;
+            let intact = first_out_of_order(&trace.events).is_none()
+                && first_broken_link(&trace.events).is_none()
+                && own.iter().filter(|event| !event.signature.is_empty()).all(|event| has_valid_signature(event));
+            if intact {
+                clean += 1;
+            }
+        }
+        (events_logged, touched, clean)
+    });
+
+    let verified_score = if participant.is_verified { 40 } else { 0 };
+    let activity_score = events_logged.min(30);
+    let integrity_score = (clean * 30).checked_div(touched).unwrap_or(0);
+    Ok((verified_score + activity_score + integrity_score) as u8)
+}
+
+// Events submitted by an IC principal, oldest first
+#[query]
+fn events_by_principal(p: Principal) -> Vec<SupplyChainEvent> {
+    EVENTS.with_borrow(|events| {
+        let mut matching: Vec<SupplyChainEvent> = events.values()
+            .filter(|event| event.created_by == p)
+            .cloned()
+            .collect();
+        matching.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)));
+        matching
+    })
+}
+
+// Every event logged by a participant, newest first
+#[query]
+fn participant_activity(participant_id: String) -> Vec<SupplyChainEvent> {
+    EVENTS.with_borrow(|events| {
+        let mut activity: Vec<SupplyChainEvent> = events.values()
+            .filter(|event| event.actor_id == participant_id)
+            .cloned()
+            .collect();
+        activity.sort_by(|a, b| b.timestamp.cmp(&a.timestamp).then_with(|| b.id.cmp(&a.id)));
+        activity
+    })
+}
+
+#[query]
+fn allowed_event_types(role: ParticipantRole) -> Vec<EventType> {
+    ALL_EVENT_TYPES.iter()
+        .filter(|event_type| is_role_permitted(&role, event_type))
+        .cloned()
+        .collect()
+}
+
+// Every event system-wide within [start, end], oldest first. Windows holding more
+// than MAX_RESPONSE_EVENTS events return ResponseTooLarge; retry with a narrower one.
+#[query]
+fn events_in_window(start: u64, end: u64) -> Result<Vec<SupplyChainEvent>, RouteSyncError> {
+    if start > end {
+        return Err(RouteSyncError::InvalidInput("Window start is after its end".to_string()));
+    }
+
+    let mut matching: Vec<SupplyChainEvent> = EVENTS.with_borrow(|events| {
+        let in_window = || events.values().filter(|event| (start..=end).contains(&event.timestamp));
+        if in_window().count() > MAX_RESPONSE_EVENTS {
+            return Err(RouteSyncError::ResponseTooLarge);
+        }
+        Ok(in_window().cloned().collect())
+    })?;
+    matching.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)));
+    Ok(matching)
+}
+
+#[query]
+fn event_throughput(event_type: EventType, start: u64, end: u64) -> u64 {
+    if start > end {
+        return 0;
+    }
+
+    EVENTS.with_borrow(|events| {
+        events.values()
+            .filter(|event| event.event_type == event_type && (start..=end).contains(&event.timestamp))
+            .count() as u64
+    })
+}
+
+#[query]
+fn get_event_counts() -> Vec<(String, u64)> {
+    TRACES.with_borrow(|traces| {
+        let mut counts: Vec<(String, u64)> = traces.values()
+            .map(|trace| (trace.product_id.clone(), trace.events.len() as u64))
+            .collect();
+        // Highest counts first, ties broken by product id for stable ordering
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts
+    })
+}
+
+// Helper function to read the size of the wasm heap; zero outside the canister
+fn heap_size_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE_BYTES
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+#[query]
+fn memory_stats() -> MemStats {
+    MemStats {
+        products: PRODUCTS.with_borrow(|products| products.len() as u64),
+        events: EVENTS.with_borrow(|events| events.len() as u64),
+        traces: TRACES.with_borrow(|traces| traces.len() as u64),
+        participants: PARTICIPANTS.with_borrow(|participants| participants.len() as u64),
+        heap_bytes: heap_size_bytes(),
+        stable_bytes: system::stable_size_pages() * WASM_PAGE_SIZE_BYTES,
+    }
+}
+
+#[query]
+fn get_statistics() -> Statistics {
+    let (total_events, events_by_type) = EVENTS.with_borrow(|events| {
+        let events_by_type = ALL_EVENT_TYPES.iter()
+            .map(|event_type| {
+                let count = events.values().filter(|event| event.event_type == *event_type).count();
+                (event_type.clone(), count as u64)
+            })
+            .collect();
+        (events.len() as u64, events_by_type)
+    });
+    let (total_participants, verified_participants) = PARTICIPANTS.with_borrow(|participants| {
+        let verified = participants.values().filter(|participant| participant.is_verified).count();
+        (participants.len() as u64, verified as u64)
+    });
+
+    Statistics {
+        total_products: PRODUCTS.with_borrow(|products| products.len() as u64),
+        total_events,
+        total_participants,
+        verified_participants,
+        events_by_type,
+    }
+}
+
+#[query]
+fn verify_product_authenticity(product_id: String) -> Result<bool, RouteSyncError> {
+    verify_product_authenticity_detailed(product_id).map(|verdict| verdict.authentic)
+}
+
+// verify_product_authenticity for each id, paired with the id in input order. Errors
+// are reported by name, and ids past MAX_VERIFY_IDS are not checked.
+#[query]
+fn verify_products_batch(product_ids: Vec<String>) -> Vec<(String, Result<bool, String>)> {
+    product_ids.into_iter()
+        .enumerate()
+        .map(|(index, product_id)| {
+            let verdict = if index < MAX_VERIFY_IDS {
+                verify_product_authenticity(product_id.clone()).map_err(|error| format!("{:?}", error))
+            } else {
+                Err(format!("At most {} product ids per batch", MAX_VERIFY_IDS))
+            };
+            (product_id, verdict)
+        })
+        .collect()
+}
+
+// Same checks as verify_product_authenticity, with a reason for each failure
+#[query]
+fn verify_product_authenticity_detailed(product_id: String) -> Result<AuthVerdict, RouteSyncError> {
+    let product = PRODUCTS.with_borrow(|products| products.get(&product_id).cloned())
+        .ok_or(RouteSyncError::ProductNotFound)?;
+    let events = TRACES.with_borrow(|traces| traces.get(&product_id).map(|trace| trace.events.clone()));
+
+    let report = authenticity_checks(&product, events.as_deref());
+    Ok(AuthVerdict {
+        authentic: report.issues.is_empty(),
+        reasons: report.issues,
+    })
+}
+
+// Helper function running the checks that define an authentic product: it has not
+// been recalled, and its trace is non-empty, chronological, correctly chained and
+// validly signed. Every authenticity query builds on this one definition; the
+// returned report leaves cold_chain_ok set, since temperature is checked separately.
+fn authenticity_checks(product: &Product, events: Option<&[SupplyChainEvent]>) -> IntegrityReport {
+    let mut issues = Vec::new();
+    if product.recalled {
+        issues.push(format!(
+            "Product recalled: {}",
+            product.recall_reason.as_deref().unwrap_or("no reason given")
+        ));
+    }
+    match events {
+        None => issues.push("Trace not found".to_string()),
+        Some([]) => issues.push("Trace has no events".to_string()),
+        Some(_) => {}
+    }
+    let events = events.unwrap_or_default();
+
+    let out_of_order = first_out_of_order(events);
+    if let Some(index) = out_of_order {
+        issues.push(format!("Event at index {} precedes the previous event", index));
+    }
+
+    let broken_link = first_broken_link(events);
+    if let Some(index) = broken_link {
+        issues.push(format!("Hash chain broken at index {}", index));
+    }
+
+    let mut signatures_ok = true;
+    for event in events.iter().filter(|event| !event.signature.is_empty()) {
+        if !has_valid_signature(event) {
+            signatures_ok = false;
+            issues.push(format!("Invalid signature on event {}", event.id));
+        }
+    }
+
+    IntegrityReport {
+        chronological_ok: out_of_order.is_none(),
+        hash_chain_ok: broken_link.is_none(),
+        signatures_ok,
+        cold_chain_ok: true,
+        issues,
+    }
+}
+
+// Check the trace against the lifecycle partial order; empty means the sequence is valid
+#[query]
+fn validate_event_sequence(product_id: String) -> Result<Vec<String>, RouteSyncError> {
+    let events = filter_trace_events(&product_id, |_| true)?;
+
+    let mut violations = Vec::new();
+    for (index, event) in events.iter().enumerate() {
+        for required in required_predecessors(&event.event_type) {
+            if events[..index].iter().any(|earlier| &earlier.event_type == required) {
+                continue;
+            }
+            if events[index + 1..].iter().any(|later| &later.event_type == required) {
+                violations.push(format!("{:?} at index {} precedes {:?}", event.event_type, index, required));
+            } else {
+                violations.push(format!("{:?} at index {} has no preceding {:?}", event.event_type, index, required));
+            }
+        }
+    }
+    Ok(violations)
+}
+
+#[query]
+fn verify_event_chain(product_id: String) -> Result<bool, RouteSyncError> {
+    if !PRODUCTS.with_borrow(|products| products.contains_key(&product_id)) {
+        return Err(RouteSyncError::ProductNotFound);
+    }
+
+    TRACES.with_borrow(|traces| {
+        let trace = traces.get(&product_id).ok_or(RouteSyncError::TraceNotFound)?;
+
+        Ok(first_broken_link(&trace.events).is_none())
+    })
+}
+
+// Signatures are checked against the key the actor had when the event was
+// recorded, so rotating a participant's key does not invalidate past events
+#[query]
+fn verify_event_signature(event_id: String) -> Result<bool, RouteSyncError> {
+    let event = EVENTS.with_borrow(|events| events.get(&event_id).cloned())
+        .ok_or(RouteSyncError::EventNotFound)?;
+    Ok(has_valid_signature(&event))
+}
+
+#[query]
+fn full_integrity_report(product_id: String) -> Result<IntegrityReport, RouteSyncError> {
+    let product = PRODUCTS.with_borrow(|products| products.get(&product_id).cloned())
+        .ok_or(RouteSyncError::ProductNotFound)?;
+    let events = TRACES.with_borrow(|traces| traces.get(&product_id).map(|trace| trace.events.clone()))
+        .ok_or(RouteSyncError::TraceNotFound)?;
+
+    // Authenticity checks, then the cold chain on top
+    let mut report = authenticity_checks(&product, Some(&events));
+    add_cold_chain_checks(&mut report, &product, &events);
+    Ok(report)
+}
+
+// Helper function to flag readings outside the product's temperature range
+fn add_cold_chain_checks(report: &mut IntegrityReport, product: &Product, events: &[SupplyChainEvent]) {
+    let (min_temp, max_temp) = product.temp_range.unwrap_or(COLD_CHAIN_RANGE);
+    for event in events {
+        if let Some(temperature) = event.temperature.filter(|t| *t < min_temp || *t > max_temp) {
+            report.cold_chain_ok = false;
+            report.issues.push(format!(
+                "Temperature {} outside [{}, {}] on event {}",
+                temperature, min_temp, max_temp, event.id
+            ));
+        }
+    }
+}
+
+// Label of the certified tree holding each product's certificate digest
+const CERTIFICATE_TREE_LABEL: &[u8] = b"certificates";
+
+// Helper function to build a product's certificate body. It only depends on the
+// product and the hash of its newest event, which commits to the whole chain, so
+// certifying it after a change costs the same however long the trace is.
+fn certificate_body(product: &Product, events: &[SupplyChainEvent]) -> serde_json::Value {
+    json!({
+        "canister_id": system::id().to_text(),
+        "product": {
+            "id": product.id,
+            "name": product.name,
+            "manufacturer": product.manufacturer,
+            "batch_number": product.batch_number,
+            "production_date": product.production_date,
+            "expiry_date": product.expiry_date,
+            "certifications": product.certifications,
+            "recalled": product.recalled,
+        },
+        "event_count": events.len(),
+        "trace_head": events.last().map(|event| event.hash.as_str()).unwrap_or_default(),
+    })
+}
+
+// Helper function to refresh a product's certified digest after it changed and
+// publish the new root hash. Products without a trace are dropped from the tree.
+fn certify_product(product_id: &str) {
+    let digest: Option<Hash> = PRODUCTS.with_borrow(|products| {
+        TRACES.with_borrow(|traces| {
+            let product = products.get(product_id)?;
+            let trace = traces.get(product_id)?;
+            Some(Sha256::digest(certificate_body(product, &trace.events).to_string()).into())
+        })
+    });
+    CERTIFIED_DIGESTS.with_borrow_mut(|tree| match digest {
+        Some(digest) => tree.insert(product_id.to_string(), digest),
+        None => tree.delete(product_id.as_bytes()),
+    });
+    publish_certified_data();
+}
+
+// Helper function to rebuild every certified digest from scratch
+fn certify_all_products() {
+    let product_ids: Vec<String> = PRODUCTS.with_borrow(|products| products.keys().cloned().collect());
+    CERTIFIED_DIGESTS.set(RbTree::new());
+    for product_id in &product_ids {
+        certify_product(product_id);
+    }
+    publish_certified_data();
+}
+
+// Helper function to set the canister's certified data to the digest tree's root
+fn publish_certified_data() {
+    let root = CERTIFIED_DIGESTS.with_borrow(|tree| labeled_hash(CERTIFICATE_TREE_LABEL, &tree.root_hash()));
+    system::set_certified_data(&root);
+}
+
+// Self-contained JSON certificate for archiving off-chain. `digest` is the sha256 of
+// the serialized certificate body, and the canister certifies every product's digest
+// in a hash tree whose root it sets as its certified data. `ic_certificate` is the
+// subnet-signed certificate over that root, and `witness` is the CBOR hash tree
+// proving the digest under certificates/<product id>, so anyone holding the IC root
+// key can check the body was issued by this canister. The body pins the trace by its
+// head hash; `integrity` is computed when the certificate is requested and is not
+// itself certified. `ic_certificate` is null unless this is called as a query.
+#[query]
+fn authenticity_certificate(product_id: String) -> Result<String, RouteSyncError> {
+    let product = PRODUCTS.with_borrow(|products| products.get(&product_id).cloned())
+        .ok_or(RouteSyncError::ProductNotFound)?;
+    let events = TRACES.with_borrow(|traces| traces.get(&product_id).map(|trace| trace.events.clone()))
+        .ok_or(RouteSyncError::TraceNotFound)?;
+
+    let body = certificate_body(&product, &events);
+    let mut report = authenticity_checks(&product, Some(&events));
+    let authentic = report.issues.is_empty();
+    add_cold_chain_checks(&mut report, &product, &events);
+    let digest = hex::encode(Sha256::digest(body.to_string()));
+    let witness = CERTIFIED_DIGESTS.with_borrow(|tree| {
+        let witness = labeled(CERTIFICATE_TREE_LABEL, tree.witness(product_id.as_bytes()));
+        let mut serializer = serde_cbor::Serializer::new(Vec::new());
+        serializer.self_describe().expect("Failed to write CBOR tag");
+        witness.serialize(&mut serializer).expect("Failed to serialize witness");
+        serializer.into_inner()
+    });
+
+    Ok(json!({
+        "certificate": body,
+        "digest": digest,
+        "integrity": {
+            "authentic": authentic,
+            "chronological_ok": report.chronological_ok,
+            "hash_chain_ok": report.hash_chain_ok,
+            "signatures_ok": report.signatures_ok,
+            "cold_chain_ok": report.cold_chain_ok,
+            "issues": report.issues,
+        },
+        "generated_at": get_current_timestamp(),
+        "ic_certificate": system::data_certificate().map(hex::encode),
+        "witness": hex::encode(witness),
+    })
+    .to_string())
+}
+
+// Omitted bounds default to the product's temp_range, then to COLD_CHAIN_RANGE
+#[query]
+fn check_cold_chain(
+    product_id: String,
+    min_temp: Option<f64>,
+    max_temp: Option<f64>,
+) -> Result<Vec<SupplyChainEvent>, RouteSyncError> {
+    let (default_min, default_max) = PRODUCTS.with_borrow(|products| products.get(&product_id).map(|product| product.temp_range))
+        .ok_or(RouteSyncError::ProductNotFound)?
+        .unwrap_or(COLD_CHAIN_RANGE);
+    let min_temp = min_temp.unwrap_or(default_min);
+    let max_temp = max_temp.unwrap_or(default_max);
+
+    // Events without a temperature reading are not treated as breaches
+    filter_trace_events(&product_id, |event| {
+        matches!(event.temperature, Some(t) if t < min_temp || t > max_temp)
+    })
+}
+
+#[query]
+fn check_humidity_range(product_id: String, min: f64, max: f64) -> Result<Vec<SupplyChainEvent>, RouteSyncError> {
+    // Events without a humidity reading are ignored
+    filter_trace_events(&product_id, |event| {
+        matches!(event.humidity, Some(h) if h < min || h > max)
+    })
+}
+
+#[query]
+fn get_product_events_by_type(product_id: String, event_type: EventType) -> Result<Vec<SupplyChainEvent>, RouteSyncError> {
+    let mut matching = filter_trace_events(&product_id, |event| event.event_type == event_type)?;
+    matching.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)));
+    Ok(matching)
+}
+
+#[query]
+fn get_latest_event(product_id: String) -> Result<SupplyChainEvent, RouteSyncError> {
+    filter_trace_events(&product_id, |_| true)?
+        .into_iter()
+        .max_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)))
+        .ok_or(RouteSyncError::NoEvents)
+}
+
+#[query]
+fn export_traces(product_ids: Vec<String>) -> Result<Vec<SupplyChainTrace>, RouteSyncError> {
+    if product_ids.len() > MAX_EXPORT_IDS {
+        return Err(RouteSyncError::InvalidInput(format!("At most {} product ids per export", MAX_EXPORT_IDS)));
+    }
+
+    // Unknown ids are skipped silently
+    let exported: Vec<SupplyChainTrace> = TRACES.with_borrow(|traces| {
+        product_ids.iter()
+            .filter_map(|product_id| traces.get(product_id).cloned())
+            .collect()
+    });
+
+    let total_events: usize = exported.iter().map(|trace| trace.events.len()).sum();
+    if total_events > MAX_RESPONSE_EVENTS {
+        return Err(RouteSyncError::ResponseTooLarge);
+    }
+    Ok(exported)
+}
+
+#[query]
+fn temperature_summary(product_id: String) -> Result<TempSummary, RouteSyncError> {
+    let readings: Vec<f64> = filter_trace_events(&product_id, |_| true)?
+        .iter()
+        .filter_map(|event| event.temperature)
+        .filter(|temperature| !temperature.is_nan())
+        .collect();
+    if readings.is_empty() {
+        return Err(RouteSyncError::NoTemperatureData);
+    }
+
+    let count = readings.len();
+    Ok(TempSummary {
+        count: count as u64,
+        min: readings.iter().cloned().fold(f64::INFINITY, f64::min),
+        max: readings.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+        avg: readings.iter().sum::<f64>() / count as f64,
+    })
+}
+
+// Seconds spent above max_temp. An interval between consecutive events accrues
+// only when both carry a reading and the earlier one exceeds the threshold.
+#[query]
+fn excursion_duration(product_id: String, max_temp: f64) -> Result<u64, RouteSyncError> {
+    let events = filter_trace_events(&product_id, |_| true)?;
+    let millis: u64 = events.windows(2)
+        .filter(|pair| {
+            pair[0].temperature.is_some_and(|temperature| temperature > max_temp)
+                && pair[1].temperature.is_some()
+        })
+        .map(|pair| pair[1].timestamp.saturating_sub(pair[0].timestamp))
+        .sum();
+    Ok(millis / 1000)
+}
+
+// Sensor readings recorded in a product's trace, oldest first
+#[query]
+fn sensor_readings(product_id: String) -> Vec<SupplyChainEvent> {
+    filter_trace_events(&product_id, |event| event.event_type == EventType::SensorReading)
+        .unwrap_or_default()
+}
+
+#[query]
+fn compare_traces(a: String, b: String) -> Result<TraceDiff, RouteSyncError> {
+    let count_by_type = |events: &[SupplyChainEvent]| -> Vec<(EventType, u64)> {
+        ALL_EVENT_TYPES.iter()
+            .map(|event_type| {
+                let count = events.iter().filter(|event| event.event_type == *event_type).count();
+                (event_type.clone(), count as u64)
+            })
+            .collect()
+    };
+    let a_counts = count_by_type(&filter_trace_events(&a, |_| true)?);
+    let b_counts = count_by_type(&filter_trace_events(&b, |_| true)?);
+
+    let only_in = |ours: &[(EventType, u64)], theirs: &[(EventType, u64)]| -> Vec<EventType> {
+        ours.iter()
+            .zip(theirs)
+            .filter(|((_, ours), (_, theirs))| *ours > 0 && *theirs == 0)
+            .map(|((event_type, _), _)| event_type.clone())
+            .collect()
+    };
+    Ok(TraceDiff {
+        only_in_a: only_in(&a_counts, &b_counts),
+        only_in_b: only_in(&b_counts, &a_counts),
+        a_counts,
+        b_counts,
+    })
+}
+
+// Pairs of event ids sharing type, location and actor within DUPLICATE_EVENT_WINDOW_MS
+#[query]
+fn find_duplicate_events(product_id: String) -> Result<Vec<(String, String)>, RouteSyncError> {
+    let events = filter_trace_events(&product_id, |_| true)?;
+
+    let mut duplicates = Vec::new();
+    for (index, event) in events.iter().enumerate() {
+        // Trace events are chronological, so stop once the window is passed
+        for later in events[index + 1..].iter()
+            .take_while(|later| later.timestamp.saturating_sub(event.timestamp) <= DUPLICATE_EVENT_WINDOW_MS)
+        {
+            if later.event_type == event.event_type
+                && later.location == event.location
+                && later.actor_id == event.actor_id
+            {
+                duplicates.push((event.id.clone(), later.id.clone()));
+            }
+        }
+    }
+    Ok(duplicates)
+}
+
+// Shipping and Customs events missing coordinates, temperature or humidity
+#[query]
+fn incomplete_events(product_id: String) -> Result<Vec<SupplyChainEvent>, RouteSyncError> {
+    filter_trace_events(&product_id, |event| {
+        matches!(event.event_type, EventType::Shipping | EventType::Customs)
+            && (event.coordinates.is_none() || event.temperature.is_none() || event.humidity.is_none())
+    })
+}
+
+// Seconds between the first Shipping and the first Delivery event
+#[query]
+fn transit_duration(product_id: String) -> Result<u64, RouteSyncError> {
+    let events = filter_trace_events(&product_id, |_| true)?;
+    let first_of = |event_type: EventType| {
+        events.iter()
+            .filter(|event| event.event_type == event_type)
+            .map(|event| event.timestamp)
+            .min()
+    };
+
+    let shipped = first_of(EventType::Shipping).ok_or(RouteSyncError::IncompleteTransitData)?;
+    let delivered = first_of(EventType::Delivery).ok_or(RouteSyncError::IncompleteTransitData)?;
+    delivered.checked_sub(shipped)
+        .map(|millis| millis / 1000)
+        .ok_or(RouteSyncError::EventOutOfOrder)
+}
+
+// Percentage (0-100) of lifecycle stages with at least one event in the trace
+#[query]
+fn trace_completeness(product_id: String) -> Result<u8, RouteSyncError> {
+    let events = filter_trace_events(&product_id, |_| true)?;
+    let covered = LIFECYCLE_STAGES.iter()
+        .filter(|stage| events.iter().any(|event| &event.event_type == *stage))
+        .count();
+    Ok((covered * 100 / LIFECYCLE_STAGES.len()) as u8)
+}
+
+// (lat, lon, timestamp) of every geolocated event, in trace order
+#[query]
+fn route_path(product_id: String) -> Result<Vec<(f64, f64, u64)>, RouteSyncError> {
+    Ok(filter_trace_events(&product_id, |event| event.coordinates.is_some())?
+        .iter()
+        .filter_map(|event| event.coordinates.map(|(lat, lon)| (lat, lon, event.timestamp)))
+        .collect())
+}
+
+// (product_id, lat, lon, timestamp) of the latest geolocated event of every product
+// still in transit. Products delivered or sold after their last shipment are left out.
+#[query]
+fn fleet_positions() -> Vec<(String, f64, f64, u64)> {
+    TRACES.with_borrow(|traces| {
+        let mut positions: Vec<(String, f64, f64, u64)> = traces.values()
+            .filter_map(|trace| {
+                // Delivered once a Delivery or Retail event follows the last shipment;
+                // later sensor readings do not put the product back in transit
+                let last_shipping = trace.events.iter()
+                    .rposition(|event| event.event_type == EventType::Shipping);
+                let delivered = trace.events.iter()
+                    .skip(last_shipping.map_or(0, |index| index + 1))
+                    .any(|event| matches!(event.event_type, EventType::Delivery | EventType::Retail));
+                if delivered {
+                    return None;
+                }
+                trace.events.iter().rev()
+                    .find_map(|event| event.coordinates.map(|(lat, lon)| (trace.product_id.clone(), lat, lon, event.timestamp)))
+            })
+            .collect();
+        positions.sort_by(|a, b| a.0.cmp(&b.0));
+        positions
+    })
+}
+
+// Great-circle kilometres between consecutive geolocated events
+#[query]
+fn total_distance_km(product_id: String) -> Result<f64, RouteSyncError> {
+    let points: Vec<(f64, f64)> = filter_trace_events(&product_id, |_| true)?
+        .iter()
+        .filter_map(|event| event.coordinates)
+        .collect();
+    Ok(points.windows(2).map(|pair| haversine_km(pair[0], pair[1])).sum())
+}
+
+#[query]
+fn events_outside_corridor(
+    product_id: String,
+    corridor: Vec<(f64, f64)>,
+    tolerance_km: f64,
+) -> Result<Vec<SupplyChainEvent>, RouteSyncError> {
+    if corridor.len() < 2 {
+        return Err(RouteSyncError::InvalidInput("Corridor needs at least two points".to_string()));
+    }
+
+    // Events without coordinates are ignored
+    filter_trace_events(&product_id, |event| {
+        event.coordinates.is_some_and(|point| {
+            corridor.windows(2)
+                .map(|segment| distance_to_segment_km(point, segment[0], segment[1]))
+                .fold(f64::INFINITY, f64::min)
+                > tolerance_km
+        })
+    })
+}
+
+#[query]
+fn merged_timeline(product_ids: Vec<String>) -> Vec<SupplyChainEvent> {
+    let product_ids: HashSet<String> = product_ids.into_iter().collect();
+
+    // Unknown product ids are skipped silently
+    let mut timeline: Vec<SupplyChainEvent> = TRACES.with_borrow(|traces| {
+        product_ids.iter()
+            .filter_map(|product_id| traces.get(product_id))
+            .flat_map(|trace| trace.events.iter().cloned())
+            .collect()
+    });
+    timeline.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)));
+    timeline
+}
+
+// Estimated kg CO2e per tonne of goods over the product's shipping events.
+// Each leg contributes distance_km * emission_factor(transport_mode); legs missing
+// either value contribute zero.
+#[query]
+fn estimate_carbon(product_id: String) -> Result<f64, RouteSyncError> {
+    let shipping = filter_trace_events(&product_id, |event| event.event_type == EventType::Shipping)?;
+    Ok(shipping.iter()
+        .filter_map(|event| match (event.distance_km, &event.transport_mode) {
+            (Some(distance), Some(mode)) => Some(distance * emission_factor(mode)),
+            _ => None,
+        })
+        .sum())
+}
+
+// Chain of custody as (participant id, timestamp) pairs. The first entry is the
+// initial holder at trace creation: the sender of the first transfer, the assigned
+// custodian when custody has never been transferred, or else the product owner's
+// principal.
+#[query]
+fn custody_history(product_id: String) -> Result<Vec<(String, u64)>, RouteSyncError> {
+    let owner = PRODUCTS.with_borrow(|products| products.get(&product_id).map(|product| product.owner))
+        .ok_or(RouteSyncError::ProductNotFound)?;
+    let trace = TRACES.with_borrow(|traces| traces.get(&product_id).cloned())
+        .ok_or(RouteSyncError::TraceNotFound)?;
+
+    let transfers: Vec<&SupplyChainEvent> = trace.events.iter()
+        .filter(|event| event.custody_to.is_some())
+        .collect();
+    let initial_holder = transfers.first()
+        .map(|event| event.actor_id.clone())
+        .or(trace.current_custodian.clone())
+        .unwrap_or_else(|| owner.to_text());
+
+    let mut history = vec![(initial_holder, trace.created_at)];
+    history.extend(transfers.iter().filter_map(|event| {
+        event.custody_to.clone().map(|to| (to, event.timestamp))
+    }));
+    Ok(history)
+}
+
+// Changelog entries newer than the given cursor, oldest first
+#[query]
+fn changes_since(seq: u64) -> Vec<ChangeLogEntry> {
+    CHANGELOG.with_borrow(|changelog| {
+        changelog.iter()
+            .filter(|entry| entry.seq > seq)
+            .cloned()
+            .collect()
+    })
+}
+
+// Note: The canister interface is defined in supply_chain.did 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn principal(byte: u8) -> Principal {
+        Principal::from_slice(&[byte])
+    }
+
+    // Install the canister with `admin` as the deployer, who also makes the following calls
+    fn setup() -> Principal {
+        let admin = principal(1);
+        system::set_caller(admin);
+        init(InitConfig::default());
+        admin
+    }
+
+    fn product_input(name: &str, manufacturer: &str, batch_number: &str) -> ProductInput {
+        ProductInput {
+            name: name.to_string(),
+            description: format!("{} description", name),
+            manufacturer: manufacturer.to_string(),
+            batch_number: batch_number.to_string(),
+            ingredients: vec!["water".to_string()],
+            certifications: Vec::new(),
+            expiry_date: u64::MAX,
+            allergens: Vec::new(),
+            location: "Factory".to_string(),
+            temp_range: None,
+        }
+    }
+
+    fn create(input: ProductInput) -> Result<String, RouteSyncError> {
+        create_product(
+            input.name,
+            input.description,
+            input.manufacturer,
+            input.batch_number,
+            input.ingredients,
+            input.certifications,
+            input.expiry_date,
+            input.allergens,
+            input.location,
+            None,
+            input.temp_range,
+        )
+    }
+
+    fn new_product(name: &str) -> String {
+        create(product_input(name, "Acme", name)).unwrap()
+    }
+
+    // Each test participant signs with a key derived from its name
+    fn signing_key(name: &str) -> SigningKey {
+        SigningKey::from_bytes(&Sha256::digest(name.as_bytes()).into())
+    }
+
+    fn new_participant(name: &str, role: ParticipantRole) -> String {
+        let public_key = hex::encode(signing_key(name).verifying_key().to_bytes());
+        register_participant(name.to_string(), role, "Depot".to_string(), public_key).unwrap()
+    }
+
+    fn event_input(event_type: EventType, actor_id: &str) -> EventInput {
+        EventInput {
+            event_type,
+            location: "Warehouse".to_string(),
+            actor_id: actor_id.to_string(),
+            details: String::new(),
+            coordinates: None,
+            temperature: None,
+            humidity: None,
+            signature: Vec::new(),
+            distance_km: None,
+            transport_mode: None,
+            documents: Vec::new(),
+            shipment_id: None,
+            tags: Vec::new(),
+        }
+    }
+
+    // Sign the input with the actor's key
+    fn sign(product_id: &str, mut input: EventInput) -> EventInput {
+        let actor = get_participant(input.actor_id.clone()).unwrap();
+        let payload = get_signing_payload(product_id.to_string(), input.clone()).unwrap();
+        input.signature = signing_key(&actor.name).sign(&payload).to_bytes().to_vec();
+        input
+    }
+
+    // Sign the input and log it through add_supply_chain_event
+    fn log_event(product_id: &str, input: EventInput) -> Result<String, RouteSyncError> {
+        let input = sign(product_id, input);
+        add_supply_chain_event(
+            product_id.to_string(),
+            input.event_type,
+            input.location,
+            input.actor_id,
+            input.details,
+            input.coordinates,
+            input.temperature,
+            input.humidity,
+            input.signature,
+            input.distance_km,
+            input.transport_mode,
+            input.documents,
+            input.shipment_id,
+            input.tags,
+        )
+    }
+
+    // Round-trip all state through the same Candid encoding pre_upgrade/post_upgrade use
+    fn simulate_upgrade() {
+        let bytes = candid::encode_args((STABLE_STATE_VERSION, snapshot_state())).unwrap();
+        assert_eq!(get_products_count(), 0);
+        let (version, state) = candid::decode_args::<(u32, StableState)>(&bytes).unwrap();
+        assert_eq!(version, STABLE_STATE_VERSION);
+        restore_state(state);
+    }
+
+    #[test]
+    fn state_survives_upgrade() {
+        setup();
+        let product_id = new_product("Olive Oil");
+
+        simulate_upgrade();
+
+        assert_eq!(get_product(product_id.clone()).unwrap().name, "Olive Oil");
+        assert!(get_supply_chain_trace(product_id.clone()).is_some());
+        assert_eq!(list_admins(), vec![principal(1)]);
+        // The id counter is restored too, so new ids don't collide with old ones
+        assert_ne!(new_product("Vinegar"), product_id);
+        assert_eq!(get_products_count(), 2);
+    }
+
+    #[test]
+    fn unversioned_snapshot_is_rejected() {
+        setup();
+        new_product("Olive Oil");
+        let bytes = candid::encode_args((snapshot_state(),)).unwrap();
+        assert!(candid::decode_args::<(u32, StableState)>(&bytes).is_err());
+    }
+
+    #[test]
+    fn state_needs_no_init_for_allocation() {
+        system::set_caller(principal(2));
+        assert!(get_all_products().is_empty());
+
+        let product_id = new_product("Olive Oil");
+
+        assert_eq!(get_all_products().len(), 1);
+        assert_eq!(get_product(product_id).unwrap().owner, principal(2));
+    }
+
+    #[test]
+    fn delete_product_removes_trace_and_events() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        let first = log_event(&product_id, event_input(EventType::Production, &maker)).unwrap();
+        let second = log_event(&product_id, event_input(EventType::QualityCheck, &maker)).unwrap();
+
+        delete_product(product_id.clone()).unwrap();
+
+        assert!(matches!(get_product(product_id.clone()), Err(RouteSyncError::ProductNotFound)));
+        assert!(get_supply_chain_trace(product_id.clone()).is_none());
+        assert!(get_event(first).is_err());
+        assert!(get_event(second).is_err());
+        assert!(matches!(delete_product(product_id), Err(RouteSyncError::ProductNotFound)));
+    }
+
+    #[test]
+    fn only_verified_participants_are_listed() {
+        setup();
+        let verified = new_participant("Verified", ParticipantRole::Supplier);
+        let unverified = new_participant("Unverified", ParticipantRole::Supplier);
+
+        assert!(verify_participant(verified.clone()).unwrap().is_verified);
+
+        let listed: Vec<String> = get_verified_participants().into_iter().map(|p| p.id).collect();
+        assert_eq!(listed, vec![verified.clone()]);
+        assert!(!listed.contains(&unverified));
+
+        assert!(!revoke_participant(verified).unwrap().is_verified);
+        assert!(get_verified_participants().is_empty());
+    }
+
+    #[test]
+    fn only_admins_verify_participants() {
+        setup();
+        let participant_id = new_participant("Supplier", ParticipantRole::Supplier);
+
+        system::set_caller(principal(2));
+        assert!(matches!(verify_participant(participant_id.clone()), Err(RouteSyncError::Unauthorized)));
+        assert!(!get_participant(participant_id).unwrap().is_verified);
+    }
+
+    #[test]
+    fn event_actor_must_be_registered() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        verify_participant(maker.clone()).unwrap();
+
+        let unknown = add_supply_chain_event(
+            product_id.clone(),
+            EventType::Production,
+            "Factory".to_string(),
+            "part_unknown".to_string(),
+            String::new(),
+            None,
+            None,
+            None,
+            Vec::new(),
+            None,
+            None,
+            Vec::new(),
+            None,
+            Vec::new(),
+        );
+        assert!(matches!(unknown, Err(RouteSyncError::ParticipantNotFound)));
+
+        let event_id = log_event(&product_id, event_input(EventType::Production, &maker)).unwrap();
+        let event = get_event(event_id).unwrap();
+        assert_eq!(event.actor, "Maker");
+        assert_eq!(event.actor_role, ParticipantRole::Manufacturer);
+    }
+
+    #[test]
+    fn unverified_actor_is_rejected_when_required() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        set_require_verified_actors(true).unwrap();
+
+        let result = log_event(&product_id, event_input(EventType::Production, &maker));
+        assert!(matches!(result, Err(RouteSyncError::ParticipantNotVerified)));
+
+        verify_participant(maker.clone()).unwrap();
+        assert!(log_event(&product_id, event_input(EventType::Production, &maker)).is_ok());
+    }
+
+    #[test]
+    fn events_by_type_without_matches_is_empty() {
+        setup();
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        system::set_timestamp(2_000);
+        let first_product = new_product("Olive Oil");
+        let later = log_event(&first_product, event_input(EventType::Packaging, &maker)).unwrap();
+        system::set_timestamp(1_000);
+        let second_product = new_product("Vinegar");
+        let earlier = log_event(&second_product, event_input(EventType::Packaging, &maker)).unwrap();
+
+        assert!(get_events_by_type(EventType::Customs).is_empty());
+        let ids: Vec<String> = get_events_by_type(EventType::Packaging).into_iter().map(|event| event.id).collect();
+        assert_eq!(ids, vec![earlier, later]);
+    }
+
+    #[test]
+    fn products_by_manufacturer_match_case_insensitively() {
+        setup();
+        system::set_timestamp(2_000);
+        let later = create(product_input("Later", "Acme Foods", "B1")).unwrap();
+        system::set_timestamp(1_000);
+        let earlier = create(product_input("Earlier", "ACME FOODS", "B2")).unwrap();
+        create(product_input("Other", "Globex", "B3")).unwrap();
+
+        let ids: Vec<String> = get_products_by_manufacturer("acme foods".to_string())
+            .into_iter()
+            .map(|product| product.id)
+            .collect();
+        assert_eq!(ids, vec![earlier, later]);
+        assert!(get_products_by_manufacturer("  ".to_string()).is_empty());
+    }
+
+    #[test]
+    fn recall_downgrades_authenticity() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        log_event(&product_id, event_input(EventType::Packaging, &maker)).unwrap();
+        assert!(verify_product_authenticity(product_id.clone()).unwrap());
+
+        recall_product(product_id.clone(), "Contaminated batch".to_string()).unwrap();
+
+        let product = get_product(product_id.clone()).unwrap();
+        assert!(product.recalled);
+        assert_eq!(product.recall_reason.as_deref(), Some("Contaminated batch"));
+        assert_eq!(get_recalled_products().len(), 1);
+        assert!(!verify_product_authenticity(product_id).unwrap());
+    }
+
+    #[test]
+    fn thousand_products_in_one_tick_do_not_collide() {
+        setup();
+        let ids: HashSet<String> = (0..1000)
+            .map(|n| create(product_input("Olive Oil", "Acme", &format!("B{}", n))).unwrap())
+            .collect();
+
+        assert_eq!(ids.len(), 1000);
+        assert_eq!(get_products_count(), 1000);
+    }
+
+    #[test]
+    fn events_near_uses_radius() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        let mut paris = event_input(EventType::Packaging, &maker);
+        paris.coordinates = Some((48.8566, 2.3522));
+        let nearby = log_event(&product_id, paris).unwrap();
+        let mut berlin = event_input(EventType::QualityCheck, &maker);
+        berlin.coordinates = Some((52.52, 13.405));
+        log_event(&product_id, berlin).unwrap();
+
+        let ids: Vec<String> = get_events_near(48.86, 2.35, 50.0).into_iter().map(|event| event.id).collect();
+        assert_eq!(ids, vec![nearby]);
+        assert!(get_events_near(91.0, 0.0, 50.0).is_empty());
+        assert!(get_events_near(0.0, 181.0, 50.0).is_empty());
+    }
+
+    #[test]
+    fn search_matches_description_only() {
+        setup();
+        let mut input = product_input("Olive Oil", "Acme", "B1");
+        input.description = "Cold pressed in Crete".to_string();
+        let product_id = create(input).unwrap();
+        new_product("Vinegar");
+
+        let ids: Vec<String> = search_products("CRETE".to_string()).into_iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![product_id]);
+    }
+
+    #[test]
+    fn search_without_match_or_with_short_term_is_empty() {
+        setup();
+        new_product("Olive Oil");
+
+        assert!(search_products("saffron".to_string()).is_empty());
+        assert!(search_products("o".to_string()).is_empty());
+    }
+
+    #[test]
+    fn event_older_than_trace_head_is_rejected() {
+        setup();
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        system::set_timestamp(2_000);
+        let product_id = new_product("Olive Oil");
+
+        let mut event = build_event(&product_id, sign(&product_id, event_input(EventType::Packaging, &maker))).unwrap();
+        event.timestamp = 1_000;
+        assert!(matches!(append_event(event), Err(RouteSyncError::EventOutOfOrder)));
+        assert_eq!(get_supply_chain_trace(product_id).unwrap().events.len(), 1);
+    }
+
+    #[test]
+    fn product_events_filter_by_type() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        log_event(&product_id, event_input(EventType::Packaging, &maker)).unwrap();
+        let check = log_event(&product_id, event_input(EventType::QualityCheck, &maker)).unwrap();
+        log_event(&product_id, event_input(EventType::Shipping, &maker)).unwrap();
+
+        let ids: Vec<String> = get_product_events_by_type(product_id, EventType::QualityCheck)
+            .unwrap()
+            .into_iter()
+            .map(|event| event.id)
+            .collect();
+        assert_eq!(ids, vec![check]);
+        assert!(matches!(
+            get_product_events_by_type("prod_missing".to_string(), EventType::QualityCheck),
+            Err(RouteSyncError::ProductNotFound)
+        ));
+    }
+
+    #[test]
+    fn add_certification_skips_duplicates() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        new_product("Vinegar");
+
+        add_certification(product_id.clone(), "EU Organic".to_string()).unwrap();
+        add_certification(product_id.clone(), "eu organic".to_string()).unwrap();
+
+        assert_eq!(get_product(product_id.clone()).unwrap().certifications, vec!["EU Organic".to_string()]);
+        let ids: Vec<String> = get_products_by_certification("EU ORGANIC".to_string())
+            .into_iter()
+            .map(|product| product.id)
+            .collect();
+        assert_eq!(ids, vec![product_id]);
+    }
+
+    #[test]
+    fn amendment_keeps_original_in_trace() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        let original = log_event(&product_id, event_input(EventType::Packaging, &maker)).unwrap();
+
+        amend_event(original.clone(), "Packed in Lyon".to_string()).unwrap();
+
+        let events = get_supply_chain_trace(product_id.clone()).unwrap().events;
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[1].id, original);
+        assert!(events[1].superseded);
+        assert_eq!(events[2].amends.as_deref(), Some(original.as_str()));
+        assert_eq!(events[2].details, "Packed in Lyon");
+        assert!(verify_product_authenticity(product_id).unwrap());
+        assert!(amend_event(original, "Again".to_string()).is_err());
+    }
+
+    #[test]
+    fn participants_by_role_excludes_other_roles() {
+        setup();
+        let zeta = new_participant("Zeta Mills", ParticipantRole::Manufacturer);
+        let alpha = new_participant("Alpha Foods", ParticipantRole::Manufacturer);
+        let consumer = new_participant("Shopper", ParticipantRole::Consumer);
+
+        let ids: Vec<String> = get_participants_by_role(ParticipantRole::Manufacturer)
+            .into_iter()
+            .map(|participant| participant.id)
+            .collect();
+        assert_eq!(ids, vec![alpha, zeta]);
+        assert!(!ids.contains(&consumer));
+    }
+
+    #[test]
+    fn export_skips_unknown_ids() {
+        setup();
+        let first = new_product("Olive Oil");
+        let second = new_product("Vinegar");
+
+        let traces = export_traces(vec![first.clone(), "prod_bogus".to_string(), second.clone()]).unwrap();
+        let ids: Vec<String> = traces.into_iter().map(|trace| trace.product_id).collect();
+        assert_eq!(ids, vec![first, second]);
+
+        let too_many = vec!["prod_bogus".to_string(); MAX_EXPORT_IDS + 1];
+        assert!(matches!(export_traces(too_many), Err(RouteSyncError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn event_counts_sort_descending() {
+        setup();
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        let one = new_product("One");
+        let three = new_product("Three");
+        let two = new_product("Two");
+        log_event(&three, event_input(EventType::Packaging, &maker)).unwrap();
+        log_event(&three, event_input(EventType::QualityCheck, &maker)).unwrap();
+        log_event(&two, event_input(EventType::Packaging, &maker)).unwrap();
+
+        assert_eq!(get_event_counts(), vec![(three, 3), (two, 2), (one, 1)]);
+    }
+
+    #[test]
+    fn create_product_rejects_blank_fields() {
+        setup();
+        let blank_name = product_input("  ", "Acme", "B1");
+        let blank_manufacturer = product_input("Olive Oil", "", "B1");
+        let blank_batch = product_input("Olive Oil", "Acme", " ");
+        let mut blank_ingredient = product_input("Olive Oil", "Acme", "B1");
+        blank_ingredient.ingredients.push(" ".to_string());
+
+        for input in [blank_name, blank_manufacturer, blank_batch, blank_ingredient] {
+            assert!(matches!(create(input), Err(RouteSyncError::InvalidInput(_))));
+        }
+        assert_eq!(get_products_count(), 0);
+    }
+
+    #[test]
+    fn update_product_rejects_blank_name_and_ingredients() {
+        setup();
+        let product_id = new_product("Olive Oil");
+
+        let blank_name = update_product(product_id.clone(), Some(" ".to_string()), None, None, None);
+        assert!(matches!(blank_name, Err(RouteSyncError::InvalidInput(_))));
+        let blank_ingredient = update_product(product_id.clone(), None, None, Some(vec![String::new()]), None);
+        assert!(matches!(blank_ingredient, Err(RouteSyncError::InvalidInput(_))));
+
+        let product = get_product(product_id).unwrap();
+        assert_eq!(product.name, "Olive Oil");
+        assert_eq!(product.ingredients, vec!["water".to_string()]);
+    }
+
+    #[test]
+    fn duplicate_batch_for_manufacturer_is_rejected() {
+        setup();
+        let product_id = create(product_input("Olive Oil", "Acme", "B1")).unwrap();
+
+        let duplicate = create(product_input("Olive Oil Refill", "Acme", "B1"));
+        assert!(matches!(duplicate, Err(RouteSyncError::DuplicateBatch)));
+        assert!(create(product_input("Olive Oil", "Globex", "B1")).is_ok());
+
+        let found = find_product_by_batch("Acme".to_string(), "B1".to_string()).unwrap();
+        assert_eq!(found.id, product_id);
+        assert!(find_product_by_batch("Acme".to_string(), "B2".to_string()).is_none());
+    }
+
+    #[test]
+    fn hash_chain_covers_every_event_field() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        let event_id = log_event(&product_id, event_input(EventType::Packaging, &maker)).unwrap();
+        assert!(verify_event_chain(product_id.clone()).unwrap());
+
+        TRACES.with_borrow_mut(|traces| {
+            traces.get_mut(&product_id).unwrap().events[1].location = "Elsewhere".to_string();
+        });
+        assert!(!verify_event_chain(product_id.clone()).unwrap());
+
+        // superseded is excluded, so amending leaves the chain intact
+        TRACES.with_borrow_mut(|traces| {
+            traces.get_mut(&product_id).unwrap().events[1].location = "Warehouse".to_string();
+        });
+        amend_event(event_id, "Corrected".to_string()).unwrap();
+        assert!(verify_event_chain(product_id).unwrap());
+    }
+
+    #[test]
+    fn signature_covers_every_field_and_trace_position() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        let mut input = event_input(EventType::Packaging, &maker);
+        input.temperature = Some(4.0);
+        let signed = sign(&product_id, input);
+
+        // Changing any signed field invalidates the signature
+        let mut tampered = signed.clone();
+        tampered.temperature = Some(40.0);
+        assert!(matches!(build_event(&product_id, tampered), Err(RouteSyncError::InvalidSignature)));
+
+        let event_id = log_event(&product_id, signed.clone()).unwrap();
+        assert!(verify_event_signature(event_id).unwrap());
+
+        // The same signed input cannot be replayed at a later position
+        let replay = add_events_batch(product_id.clone(), vec![signed]);
+        assert!(matches!(replay, Err(RouteSyncError::PartialBatchFailure { .. })));
+    }
+
+    #[test]
+    fn custody_transfer_requires_sender_signature_and_role() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let carrier = new_participant("Carrier", ParticipantRole::Distributor);
+        let shop = new_participant("Shop", ParticipantRole::Retailer);
+        let consumer = new_participant("Shopper", ParticipantRole::Consumer);
+
+        // Nobody holds custody until the owner assigns it, and only once
+        let payload = get_custody_signing_payload(product_id.clone(), carrier.clone(), shop.clone()).unwrap();
+        let signature = signing_key("Carrier").sign(&payload).to_bytes().to_vec();
+        let unassigned = transfer_custody(product_id.clone(), carrier.clone(), shop.clone(), signature);
+        assert!(matches!(unassigned, Err(RouteSyncError::CustodyMismatch)));
+        assign_custodian(product_id.clone(), carrier.clone()).unwrap();
+        assert!(matches!(assign_custodian(product_id.clone(), shop.clone()), Err(RouteSyncError::CustodyMismatch)));
+
+        let unsigned = transfer_custody(product_id.clone(), carrier.clone(), shop.clone(), Vec::new());
+        assert!(matches!(unsigned, Err(RouteSyncError::InvalidSignature)));
+
+        // Only the holder hands custody on
+        let payload = get_custody_signing_payload(product_id.clone(), shop.clone(), consumer.clone()).unwrap();
+        let signature = signing_key("Shop").sign(&payload).to_bytes().to_vec();
+        let by_other = transfer_custody(product_id.clone(), shop.clone(), consumer.clone(), signature);
+        assert!(matches!(by_other, Err(RouteSyncError::CustodyMismatch)));
+
+        // Consumers may not log shipping events, so they cannot hand custody over
+        let held_by_consumer = new_product("Vinegar");
+        assign_custodian(held_by_consumer.clone(), consumer.clone()).unwrap();
+        let payload = get_custody_signing_payload(held_by_consumer.clone(), consumer.clone(), shop.clone()).unwrap();
+        let signature = signing_key("Shopper").sign(&payload).to_bytes().to_vec();
+        let by_consumer = transfer_custody(held_by_consumer, consumer, shop.clone(), signature);
+        assert!(matches!(by_consumer, Err(RouteSyncError::RoleNotPermitted)));
+
+        let payload = get_custody_signing_payload(product_id.clone(), carrier.clone(), shop.clone()).unwrap();
+        let signature = signing_key("Carrier").sign(&payload).to_bytes().to_vec();
+        transfer_custody(product_id.clone(), carrier.clone(), shop.clone(), signature).unwrap();
+
+        let trace = get_supply_chain_trace(product_id.clone()).unwrap();
+        assert_eq!(trace.current_custodian, Some(shop.clone()));
+        let handover = trace.events.last().unwrap();
+        assert_eq!(handover.custody_to, Some(shop));
+        assert!(has_valid_signature(handover));
+
+        undo_last_event(product_id.clone()).unwrap();
+        assert_eq!(get_supply_chain_trace(product_id).unwrap().current_custodian, Some(carrier));
+    }
+
+    #[test]
+    fn merge_reassigns_events_and_keeps_them_verifiable() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let keep = new_participant("Carrier", ParticipantRole::Distributor);
+        let duplicate = new_participant("Carrier Ltd", ParticipantRole::Distributor);
+        new_participant("Shop", ParticipantRole::Retailer);
+        let shipped = log_event(&product_id, event_input(EventType::Shipping, &duplicate)).unwrap();
+
+        // Custody handed to the duplicate registration is moved to the kept one too
+        assign_custodian(product_id.clone(), keep.clone()).unwrap();
+        let payload = get_custody_signing_payload(product_id.clone(), keep.clone(), duplicate.clone()).unwrap();
+        let signature = signing_key("Carrier").sign(&payload).to_bytes().to_vec();
+        transfer_custody(product_id.clone(), keep.clone(), duplicate.clone(), signature).unwrap();
+        assert_eq!(get_participants().len(), 3);
+
+        merge_participants(keep.clone(), duplicate.clone()).unwrap();
+
+        assert_eq!(get_participants().len(), 2);
+        assert!(get_participant(duplicate.clone()).is_err());
+        let event = get_event(shipped.clone()).unwrap();
+        assert_eq!(event.actor_id, keep);
+        assert_eq!(event.actor, "Carrier");
+        assert!(verify_event_signature(shipped).unwrap());
+
+        let trace = get_supply_chain_trace(product_id.clone()).unwrap();
+        assert_eq!(trace.current_custodian, Some(keep.clone()));
+        assert_eq!(trace.events.last().unwrap().custody_to, Some(keep));
+        assert!(trace.events.iter().all(|event| event.actor_id != duplicate));
+        assert!(verify_event_chain(product_id.clone()).unwrap());
+        assert!(full_integrity_report(product_id).unwrap().signatures_ok);
+    }
+
+    #[test]
+    fn merge_requires_matching_roles() {
+        setup();
+        let carrier = new_participant("Carrier", ParticipantRole::Distributor);
+        let shop = new_participant("Shop", ParticipantRole::Retailer);
+
+        assert!(matches!(merge_participants(carrier, shop), Err(RouteSyncError::InvalidInput(_))));
+        assert_eq!(get_participants().len(), 2);
+    }
+
+    #[test]
+    fn clear_all_empties_state_and_can_repeat() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        let event_id = log_event(&product_id, event_input(EventType::Packaging, &maker)).unwrap();
+
+        clear_all();
+        clear_all();
+
+        assert!(get_all_products().is_empty());
+        assert!(get_participants().is_empty());
+        assert!(get_events_by_type(EventType::Packaging).is_empty());
+        assert!(get_supply_chain_trace(product_id).is_none());
+        assert!(get_event(event_id).is_err());
+        assert_eq!(get_products_count(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unauthorized")]
+    fn clear_all_is_admin_only() {
+        setup();
+        system::set_caller(principal(2));
+        clear_all();
+    }
+
+    #[test]
+    fn products_missing_event_lists_products_without_it() {
+        setup();
+        let checked = new_product("Olive Oil");
+        let unchecked = new_product("Vinegar");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        log_event(&checked, event_input(EventType::QualityCheck, &maker)).unwrap();
+
+        let ids: Vec<String> = products_missing_event(EventType::QualityCheck)
+            .into_iter()
+            .map(|product| product.id)
+            .collect();
+        assert_eq!(ids, vec![unchecked.clone()]);
+
+        // A product without any trace counts as missing the event too
+        TRACES.with_borrow_mut(|traces| traces.remove(&checked));
+        assert_eq!(products_missing_event(EventType::QualityCheck).len(), 2);
+    }
+
+    #[test]
+    fn negative_or_non_finite_distance_is_rejected() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let carrier = new_participant("Carrier", ParticipantRole::Distributor);
+
+        for distance in [-1.0, f64::NAN, f64::INFINITY] {
+            let mut input = event_input(EventType::Shipping, &carrier);
+            input.distance_km = Some(distance);
+            assert!(matches!(log_event(&product_id, input), Err(RouteSyncError::InvalidInput(_))));
+        }
+        let mut input = event_input(EventType::Shipping, &carrier);
+        input.distance_km = Some(120.5);
+        let event_id = log_event(&product_id, input).unwrap();
+        assert_eq!(get_event(event_id).unwrap().distance_km, Some(120.5));
+    }
+
+    #[test]
+    fn out_of_range_readings_are_rejected() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        let with = |change: fn(&mut EventInput)| {
+            let mut input = event_input(EventType::Packaging, &maker);
+            change(&mut input);
+            input
+        };
+
+        let rejected = [
+            with(|input| input.coordinates = Some((90.1, 0.0))),
+            with(|input| input.coordinates = Some((-90.1, 0.0))),
+            with(|input| input.coordinates = Some((0.0, 180.1))),
+            with(|input| input.coordinates = Some((0.0, -180.1))),
+            with(|input| input.coordinates = Some((f64::NAN, 0.0))),
+            with(|input| input.temperature = Some(f64::NAN)),
+            with(|input| input.temperature = Some(f64::NEG_INFINITY)),
+            with(|input| input.humidity = Some(f64::INFINITY)),
+        ];
+        for input in rejected {
+            assert!(matches!(log_event(&product_id, input), Err(RouteSyncError::InvalidInput(_))));
+        }
+        assert!(log_event(&product_id, with(|input| input.coordinates = Some((90.0, -180.0)))).is_ok());
+    }
+
+    #[test]
+    fn previous_owner_cannot_mutate_after_transfer() {
+        let old_owner = setup();
+        let new_owner = principal(2);
+        let product_id = new_product("Olive Oil");
+
+        let to_anonymous = transfer_ownership(product_id.clone(), Principal::anonymous());
+        assert!(matches!(to_anonymous, Err(RouteSyncError::InvalidInput(_))));
+
+        transfer_ownership(product_id.clone(), new_owner).unwrap();
+        assert_eq!(get_product_owner(product_id.clone()).unwrap(), new_owner);
+
+        system::set_caller(old_owner);
+        assert!(matches!(archive_product(product_id.clone()), Err(RouteSyncError::Unauthorized)));
+        assert!(matches!(transfer_ownership(product_id.clone(), old_owner), Err(RouteSyncError::Unauthorized)));
+
+        system::set_caller(new_owner);
+        archive_product(product_id.clone()).unwrap();
+        assert!(get_product(product_id).unwrap().archived);
+    }
+
+    #[test]
+    fn casing_variants_collapse_to_first_seen() {
+        setup();
+        let mut input = product_input("Olive Oil", "Acme", "B1");
+        input.certifications = vec!["Organic".to_string(), "organic".to_string(), " ORGANIC ".to_string()];
+        input.ingredients = vec!["Olives".to_string(), "OLIVES".to_string(), "olives".to_string()];
+        let product_id = create(input).unwrap();
+
+        let product = get_product(product_id.clone()).unwrap();
+        assert_eq!(product.certifications, vec!["Organic".to_string()]);
+        assert_eq!(product.ingredients, vec!["Olives".to_string()]);
+
+        let ingredients = vec!["Salt".to_string(), "SALT".to_string(), "salt".to_string()];
+        let updated = update_product(product_id, None, None, Some(ingredients), None).unwrap();
+        assert_eq!(updated.ingredients, vec!["Salt".to_string()]);
+    }
+
+    #[test]
+    fn authenticity_verdicts_agree() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        log_event(&product_id, event_input(EventType::Packaging, &maker)).unwrap();
+        assert!(verify_product_authenticity(product_id.clone()).unwrap());
+
+        // A forged event breaks the chain: every view of authenticity must agree
+        TRACES.with_borrow_mut(|traces| {
+            traces.get_mut(&product_id).unwrap().events[1].details = "Forged".to_string();
+        });
+        assert!(!verify_product_authenticity(product_id.clone()).unwrap());
+        assert!(!verify_product_authenticity_detailed(product_id.clone()).unwrap().reasons.is_empty());
+        let batch = verify_products_batch(vec![product_id.clone()]);
+        assert!(matches!(batch[0].1, Ok(false)));
+        let certificate: serde_json::Value = serde_json::from_str(&authenticity_certificate(product_id).unwrap()).unwrap();
+        assert_eq!(certificate["integrity"]["authentic"], false);
+    }
+
+    #[test]
+    fn list_and_field_limits_apply_at_the_boundary() {
+        setup();
+        let limits = get_limits();
+        assert_eq!(
+            (limits.max_ingredients, limits.max_certifications, limits.max_field_len),
+            (100, 50, 256)
+        );
+        let entries = |count: usize| (0..count).map(|n| format!("entry {}", n)).collect::<Vec<_>>();
+        let error = |input: ProductInput| match create(input) {
+            Err(RouteSyncError::InvalidInput(message)) => message,
+            other => panic!("expected InvalidInput, got ok={}", other.is_ok()),
+        };
+
+        let mut input = product_input("At limit", "Acme", "B1");
+        input.ingredients = entries(100);
+        input.certifications = entries(50);
+        input.description = "d".repeat(256);
+        assert!(create(input).is_ok());
+
+        let mut input = product_input("Ingredients", "Acme", "B2");
+        input.ingredients = entries(101);
+        assert_eq!(error(input), "Too many ingredients");
+        let mut input = product_input("Certifications", "Acme", "B3");
+        input.certifications = entries(51);
+        assert_eq!(error(input), "Too many certifications");
+        let mut input = product_input("Description", "Acme", "B4");
+        input.description = "d".repeat(257);
+        assert_eq!(error(input), "Field too long");
+    }
+
+    #[test]
+    fn products_are_listed_per_owner() {
+        let first_owner = setup();
+        let first = new_product("Olive Oil");
+        let second_owner = principal(2);
+        system::set_caller(second_owner);
+        let second = new_product("Vinegar");
+
+        let ids = |products: Vec<Product>| products.into_iter().map(|product| product.id).collect::<Vec<_>>();
+        assert_eq!(ids(get_my_products()), vec![second.clone()]);
+        assert_eq!(ids(get_products_by_owner(first_owner)), vec![first.clone()]);
+        assert_eq!(ids(get_products_by_owner(second_owner)), vec![second]);
+
+        system::set_caller(first_owner);
+        assert_eq!(ids(get_my_products()), vec![first]);
+
+        system::set_caller(Principal::anonymous());
+        assert!(get_my_products().is_empty());
+    }
+
+    #[test]
+    fn certificate_digest_is_certified() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let certified_root = |tree: &RbTree<String, Hash>| labeled_hash(CERTIFICATE_TREE_LABEL, &tree.root_hash());
+
+        let certificate: serde_json::Value = serde_json::from_str(&authenticity_certificate(product_id.clone()).unwrap()).unwrap();
+        let digest = hex::decode(certificate["digest"].as_str().unwrap()).unwrap();
+        CERTIFIED_DIGESTS.with_borrow(|tree| {
+            assert_eq!(tree.get(product_id.as_bytes()).map(|leaf| leaf.to_vec()), Some(digest));
+            assert_eq!(system::certified_data(), certified_root(tree).to_vec());
+            let witness = labeled(CERTIFICATE_TREE_LABEL, tree.witness(product_id.as_bytes()));
+            assert_eq!(witness.reconstruct().to_vec(), system::certified_data());
+        });
+
+        // Any change to the product re-certifies it, and the digests survive an upgrade
+        recall_product(product_id.clone(), "Contaminated batch".to_string()).unwrap();
+        let recalled: serde_json::Value = serde_json::from_str(&authenticity_certificate(product_id.clone()).unwrap()).unwrap();
+        assert_ne!(recalled["digest"], certificate["digest"]);
+        let before_upgrade = system::certified_data();
+        simulate_upgrade();
+        assert_eq!(system::certified_data(), before_upgrade);
+
+        delete_product(product_id.clone()).unwrap();
+        CERTIFIED_DIGESTS.with_borrow(|tree| assert!(tree.get(product_id.as_bytes()).is_none()));
+    }
+
+    #[test]
+    fn redaction_scrubs_amendments_and_rehashes_from_the_event() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        let mut input = event_input(EventType::Packaging, &maker);
+        input.coordinates = Some((48.85, 2.35));
+        let original = log_event(&product_id, input).unwrap();
+        amend_event(original.clone(), "Packed by Jane Doe".to_string()).unwrap();
+        let genesis_hash = get_supply_chain_trace(product_id.clone()).unwrap().events[0].hash.clone();
+
+        redact_event(original).unwrap();
+
+        let events = get_supply_chain_trace(product_id.clone()).unwrap().events;
+        assert_eq!(events[0].hash, genesis_hash);
+        for event in &events[1..] {
+            assert_eq!(event.actor, "[redacted]");
+            assert!(event.details.is_empty());
+            assert!(event.coordinates.is_none());
+            assert_eq!(get_event(event.id.clone()).unwrap().actor, "[redacted]");
+        }
+        assert!(verify_event_chain(product_id).unwrap());
+    }
+
+    #[test]
+    fn redaction_refuses_to_repair_an_earlier_break() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        log_event(&product_id, event_input(EventType::Packaging, &maker)).unwrap();
+        let later = log_event(&product_id, event_input(EventType::QualityCheck, &maker)).unwrap();
+        TRACES.with_borrow_mut(|traces| {
+            traces.get_mut(&product_id).unwrap().events[1].details = "Forged".to_string();
+        });
+
+        assert!(matches!(redact_event(later.clone()), Err(RouteSyncError::InvalidInput(_))));
+        assert_eq!(get_event(later).unwrap().actor, "Maker");
+        assert!(!verify_event_chain(product_id).unwrap());
+    }
+
+    #[test]
+    fn rate_limit_applies_to_every_append_path() {
+        setup();
+        set_rate_limit(RateLimit { max_events: 2, window_secs: 60 }).unwrap();
+        let product_id = new_product("Olive Oil");
+        let carrier = new_participant("Carrier", ParticipantRole::Distributor);
+        let shop = new_participant("Shop", ParticipantRole::Retailer);
+
+        // The third event of the batch is over the limit
+        let batch = vec![
+            event_input(EventType::Shipping, &carrier),
+            event_input(EventType::Shipping, &carrier),
+            event_input(EventType::Shipping, &carrier),
+        ];
+        let signed: Vec<EventInput> = batch.into_iter()
+            .enumerate()
+            .map(|(index, mut input)| {
+                // Each signature pins the position the event will take in the trace
+                let payload = event_signing_payload(&product_id, 1 + index as u64, &input);
+                input.signature = signing_key("Carrier").sign(&payload).to_bytes().to_vec();
+                input
+            })
+            .collect();
+        match add_events_batch(product_id.clone(), signed) {
+            Err(RouteSyncError::PartialBatchFailure { inserted, failed }) => {
+                assert_eq!(inserted.len(), 2);
+                assert_eq!(failed, vec![2]);
+            }
+            _ => panic!("expected a partial batch failure"),
+        }
+
+        assign_custodian(product_id.clone(), carrier.clone()).unwrap();
+        let payload = get_custody_signing_payload(product_id.clone(), carrier.clone(), shop.clone()).unwrap();
+        let signature = signing_key("Carrier").sign(&payload).to_bytes().to_vec();
+        let transfer = transfer_custody(product_id.clone(), carrier.clone(), shop, signature);
+        assert!(matches!(transfer, Err(RouteSyncError::RateLimitExceeded)));
+
+        let first = get_supply_chain_trace(product_id).unwrap().events[1].id.clone();
+        assert!(matches!(amend_event(first, "Fixed".to_string()), Err(RouteSyncError::RateLimitExceeded)));
+        assert_eq!(get_rate_limit_status(carrier).unwrap().used, 2);
+    }
+
+    #[test]
+    fn idempotency_key_returns_the_first_product() {
+        setup();
+        let create_with_key = |key: &str| {
+            let input = product_input("Olive Oil", "Acme", "B1");
+            create_product(
+                input.name,
+                input.description,
+                input.manufacturer,
+                input.batch_number,
+                input.ingredients,
+                input.certifications,
+                input.expiry_date,
+                input.allergens,
+                input.location,
+                Some(key.to_string()),
+                input.temp_range,
+            )
+        };
+
+        let first = create_with_key("retry-1").unwrap();
+        let second = create_with_key("retry-1").unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(get_products_count(), 1);
+        // A fresh key is a new request, which then hits the duplicate batch check
+        assert!(matches!(create_with_key("retry-2"), Err(RouteSyncError::DuplicateBatch)));
+    }
+
+    #[test]
+    fn ids_carry_their_entity_prefix() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let participant_id = new_participant("Maker", ParticipantRole::Manufacturer);
+        let event_id = log_event(&product_id, event_input(EventType::Packaging, &participant_id)).unwrap();
+
+        assert!(product_id.starts_with("prod_"));
+        assert!(participant_id.starts_with("part_"));
+        assert!(event_id.starts_with("evt_"));
+        assert!(get_product(product_id).is_ok());
+        assert!(get_event(event_id).is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "Anonymous principal cannot be an admin")]
+    fn init_rejects_anonymous_admin() {
+        system::set_caller(principal(1));
+        init(InitConfig {
+            admins: vec![principal(1), Principal::anonymous()],
+            ..InitConfig::default()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "Anonymous principal cannot be an admin")]
+    fn init_rejects_anonymous_deployer() {
+        system::set_caller(Principal::anonymous());
+        init(InitConfig::default());
+    }
+
+    #[test]
+    fn finalized_trace_rejects_new_events() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        let packed = log_event(&product_id, event_input(EventType::Packaging, &maker)).unwrap();
+
+        finalize_trace(product_id.clone()).unwrap();
+
+        let result = log_event(&product_id, event_input(EventType::QualityCheck, &maker));
+        assert!(matches!(result, Err(RouteSyncError::TraceFinalized)));
+        let batch = add_events_batch(product_id.clone(), vec![sign(&product_id, event_input(EventType::QualityCheck, &maker))]);
+        assert!(matches!(batch, Err(RouteSyncError::PartialBatchFailure { .. })));
+        assert!(matches!(amend_event(packed, "Late fix".to_string()), Err(RouteSyncError::TraceFinalized)));
+        assert!(matches!(undo_last_event(product_id.clone()), Err(RouteSyncError::TraceFinalized)));
+        assert_eq!(get_supply_chain_trace(product_id.clone()).unwrap().events.len(), 2);
+    }
+
+    #[test]
+    fn only_admins_reopen_finalized_traces() {
+        let admin = setup();
+        let owner = principal(2);
+        system::set_caller(owner);
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        finalize_trace(product_id.clone()).unwrap();
+
+        assert!(matches!(reopen_trace(product_id.clone()), Err(RouteSyncError::Unauthorized)));
+        system::set_caller(admin);
+        reopen_trace(product_id.clone()).unwrap();
+
+        system::set_caller(owner);
+        assert!(log_event(&product_id, event_input(EventType::Packaging, &maker)).is_ok());
+    }
+
+    #[test]
+    fn fleet_positions_skip_delivered_products() {
+        setup();
+        let shipped = new_product("Olive Oil");
+        let delivered = new_product("Honey");
+        let carrier = new_participant("Carrier", ParticipantRole::Distributor);
+        for product_id in [&shipped, &delivered] {
+            let shipping = EventInput { coordinates: Some((45.0, 9.0)), ..event_input(EventType::Shipping, &carrier) };
+            log_event(product_id, shipping).unwrap();
+        }
+        log_event(&delivered, event_input(EventType::Delivery, &carrier)).unwrap();
+        let reading = EventInput { coordinates: Some((46.0, 10.0)), ..event_input(EventType::SensorReading, &carrier) };
+        log_event(&delivered, reading).unwrap();
+
+        let positions = fleet_positions();
+        assert_eq!(positions.len(), 1);
+        assert_eq!(positions[0].0, shipped);
+    }
+
+    #[test]
+    fn undo_does_not_reseal_earlier_tampering() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        log_event(&product_id, event_input(EventType::Packaging, &maker)).unwrap();
+        log_event(&product_id, event_input(EventType::QualityCheck, &maker)).unwrap();
+
+        TRACES.with_borrow_mut(|traces| {
+            traces.get_mut(&product_id).unwrap().events[1].location = "Elsewhere".to_string();
+        });
+        let removed = undo_last_event(product_id.clone()).unwrap();
+        assert_eq!(removed.event_type, EventType::QualityCheck);
+        assert!(!verify_event_chain(product_id).unwrap());
+    }
+
+    #[test]
+    fn participants_need_a_name() {
+        setup();
+        let result = register_participant("  ".to_string(), ParticipantRole::Supplier, "Depot".to_string(), String::new());
+        assert!(matches!(result, Err(RouteSyncError::InvalidInput(_))));
+
+        let input = |name: &str| ParticipantInput {
+            name: name.to_string(),
+            role: ParticipantRole::Supplier,
+            location: "Depot".to_string(),
+            public_key: hex::encode(signing_key(name).verifying_key().to_bytes()),
+        };
+        let ids = register_participants_batch(vec![input("Farm"), input(""), input("Mill")]);
+        assert_eq!(ids.len(), 3);
+        assert!(ids[1].is_empty());
+        assert!(get_participant(ids[0].clone()).is_ok() && get_participant(ids[2].clone()).is_ok());
+    }
+
+    #[test]
+    fn certificate_pins_the_trace_head() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        let certificate = || serde_json::from_str::<serde_json::Value>(&authenticity_certificate(product_id.clone()).unwrap()).unwrap();
+        let before = certificate();
+
+        log_event(&product_id, event_input(EventType::Packaging, &maker)).unwrap();
+        let after = certificate();
+        let head = get_supply_chain_trace(product_id.clone()).unwrap().events.last().unwrap().hash.clone();
+        assert_eq!(after["certificate"]["trace_head"], head);
+        assert_eq!(after["certificate"]["event_count"], 2);
+        assert_ne!(after["digest"], before["digest"]);
+        assert_eq!(after["integrity"]["authentic"], true);
+    }
+
+    #[test]
+    fn rate_limit_window_is_measured_in_seconds() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        set_rate_limit(RateLimit { max_events: 1, window_secs: 60 }).unwrap();
+        let start = get_current_timestamp();
+        log_event(&product_id, event_input(EventType::Packaging, &maker)).unwrap();
+
+        system::set_timestamp(start + 59_000);
+        let result = log_event(&product_id, event_input(EventType::QualityCheck, &maker));
+        assert!(matches!(result, Err(RouteSyncError::RateLimitExceeded)));
+
+        system::set_timestamp(start + 61_000);
+        assert!(log_event(&product_id, event_input(EventType::QualityCheck, &maker)).is_ok());
+    }
+
+    #[test]
+    fn expiry_dates_are_in_milliseconds() {
+        setup();
+        // 2026-01-01, with expiry at 2030-01-01
+        system::set_timestamp(1_767_225_600_000);
+        let mut input = product_input("Olive Oil", "Acme", "B1");
+        input.expiry_date = 1_893_456_000_000;
+        let product_id = create(input).unwrap();
+        assert!(!is_product_expired(product_id.clone()).unwrap());
+
+        // The same date in seconds lies before the production date
+        let mut input = product_input("Vinegar", "Acme", "B2");
+        input.expiry_date = 1_893_456_000;
+        assert!(matches!(create(input), Err(RouteSyncError::InvalidInput(_))));
+
+        system::set_timestamp(1_893_456_000_001);
+        assert!(is_product_expired(product_id).unwrap());
+    }
+
+    #[test]
+    fn duplicates_match_actor_id_within_five_seconds() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        let namesake = new_participant("Maker", ParticipantRole::Manufacturer);
+        let start = get_current_timestamp();
+        let log_at = |offset: u64, actor: &str| {
+            system::set_timestamp(start + offset);
+            log_event(&product_id, event_input(EventType::Packaging, actor)).unwrap()
+        };
+
+        let first = log_at(0, &maker);
+        log_at(1_000, &namesake);
+        let repeat = log_at(4_000, &maker);
+        log_at(10_000, &maker);
+
+        assert_eq!(find_duplicate_events(product_id.clone()).unwrap(), vec![(first, repeat)]);
+    }
+
+    #[test]
+    fn health_reports_uptime_in_seconds() {
+        setup();
+        system::set_timestamp(get_current_timestamp() + 90_500);
+        let status = health();
+        assert!(status.initialized);
+        assert_eq!(status.uptime_seconds, 90);
+    }
+
+    #[test]
+    fn transit_duration_is_in_seconds() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let carrier = new_participant("Carrier", ParticipantRole::Distributor);
+        let start = get_current_timestamp();
+        log_event(&product_id, event_input(EventType::Shipping, &carrier)).unwrap();
+        system::set_timestamp(start + 3_600_000);
+        log_event(&product_id, event_input(EventType::Delivery, &carrier)).unwrap();
+
+        assert_eq!(transit_duration(product_id).unwrap(), 3_600);
+    }
+
+    #[test]
+    fn excursion_duration_is_in_seconds() {
+        setup();
+        let product_id = new_product("Milk");
+        let carrier = new_participant("Carrier", ParticipantRole::Distributor);
+        let start = get_current_timestamp();
+        for (offset, temperature) in [(0, 9.0), (30_000, 10.0), (90_000, 4.0), (150_000, 5.0)] {
+            system::set_timestamp(start + offset);
+            let reading = EventInput { temperature: Some(temperature), ..event_input(EventType::SensorReading, &carrier) };
+            log_event(&product_id, reading).unwrap();
+        }
+
+        // Above 8 °C from the first reading until the cool one at 90 s
+        assert_eq!(excursion_duration(product_id, 8.0).unwrap(), 90);
+    }
+
+    #[test]
+    fn undo_keeps_genesis_and_requires_current_ownership() {
+        let admin = setup();
+        let owner = principal(2);
+        system::set_caller(owner);
+        let product_id = new_product("Olive Oil");
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        assert!(matches!(undo_last_event(product_id.clone()), Err(RouteSyncError::InvalidInput(_))));
+
+        log_event(&product_id, event_input(EventType::Packaging, &maker)).unwrap();
+        transfer_ownership(product_id.clone(), principal(3)).unwrap();
+        assert!(matches!(undo_last_event(product_id.clone()), Err(RouteSyncError::Unauthorized)));
+
+        system::set_caller(admin);
+        undo_last_event(product_id.clone()).unwrap();
+        assert!(matches!(undo_last_event(product_id.clone()), Err(RouteSyncError::InvalidInput(_))));
+        assert!(verify_product_authenticity(product_id).unwrap());
+    }
+
+    #[test]
+    fn participant_updates_are_validated() {
+        setup();
+        let maker = new_participant("Maker", ParticipantRole::Manufacturer);
+        let rotated = hex::encode(signing_key("Maker v2").verifying_key().to_bytes());
+
+        for (name, public_key) in [
+            (Some(" ".to_string()), None),
+            (None, Some("not hex".to_string())),
+            (None, Some(rotated[..62].to_string())),
+        ] {
+            let result = update_participant(maker.clone(), name, None, public_key);
+            assert!(matches!(result, Err(RouteSyncError::InvalidInput(_))));
+        }
+        assert_eq!(get_participant(maker.clone()).unwrap().name, "Maker");
+
+        let updated = update_participant(maker, Some("Maker Co".to_string()), None, Some(rotated.clone())).unwrap();
+        assert_eq!((updated.name.as_str(), updated.public_key), ("Maker Co", rotated));
+
+        let bad_key = register_participant("Farm".to_string(), ParticipantRole::Supplier, "Depot".to_string(), "abcd".to_string());
+        assert!(matches!(bad_key, Err(RouteSyncError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn batch_verification_reports_each_id() {
+        setup();
+        let product_id = new_product("Olive Oil");
+        let mut ids = vec![product_id.clone(), "prod_missing".to_string()];
+        ids.extend(std::iter::repeat_n(product_id.clone(), MAX_VERIFY_IDS));
+
+        let verdicts = verify_products_batch(ids);
+        assert_eq!(verdicts.len(), MAX_VERIFY_IDS + 2);
+        assert_eq!(verdicts[0], (product_id.clone(), Ok(true)));
+        assert_eq!(verdicts[1].1, Err("ProductNotFound".to_string()));
+        assert_eq!(verdicts[MAX_VERIFY_IDS - 1].1, Ok(true));
+        assert!(verdicts[MAX_VERIFY_IDS..].iter().all(|(id, verdict)| id == &product_id && verdict.is_err()));
+    }
+}